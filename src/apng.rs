@@ -0,0 +1,80 @@
+use std::{fs, io::{self, BufReader, Write}, path::Path};
+
+use image::{codecs::png::PngDecoder, AnimationDecoder, ColorType, DynamicImage};
+use tempfile::NamedTempFile;
+
+use crate::{chunks, convert_filter, correct_gamma, gamma_exponent, pad, padded_dimensions, quantize, resolve_filter, target_dimensions, Candidate, CompressOptions, SquashError};
+
+/// Animation control chunk, which only APNGs have
+const ACTL: [u8; 4] = *b"acTL";
+
+/// True if the png at `path` is an APNG. The first frame is all `ImageReader` decodes, so re-encoding one as a plain
+/// png would drop the animation
+pub fn is_animated(path: &Path) -> io::Result<bool> {
+    Ok(chunks::read(path)?.iter().any(|chunk| chunk.kind == ACTL))
+}
+
+/// How many times the animation loops, 0 being forever
+fn plays(path: &Path) -> io::Result<u32> {
+    let plays = chunks::read(path)?.into_iter()
+        .find(|chunk| chunk.kind == ACTL && chunk.data.len() >= 8)
+        .map_or(0, |chunk| u32::from_be_bytes([chunk.data[4], chunk.data[5], chunk.data[6], chunk.data[7]]));
+    Ok(plays)
+}
+
+/// Frame delays are stored as a fraction of a second with 16 bit parts
+fn delay_fraction(millis: f64) -> (u16, u16) {
+    if millis.round() <= u16::MAX as f64 {
+        (millis.round() as u16, 1000)
+    } else {
+        ((millis / 1000.0).round().min(u16::MAX as f64) as u16, 1)
+    }
+}
+
+/// Decodes every frame of the APNG at `path`, resizes each like a still image and encodes them as a new APNG in
+/// `temp_dir`. Frames come out of the decoder already composited, so each one is stored whole
+pub fn encode(path: &Path, temp_dir: &Path, opts: &CompressOptions) -> Result<Candidate, SquashError> {
+    let frames = PngDecoder::new(BufReader::new(fs::File::open(path)?))
+        .and_then(|decoder| decoder.apng())
+        .and_then(|apng| apng.into_frames().collect_frames())
+        .map_err(SquashError::decode)?;
+    let Some(first) = frames.first() else {
+        return Err(SquashError::Decode("animation has no frames".into()));
+    };
+    let (width, height) = first.buffer().dimensions();
+    let (nwidth, nheight) = target_dimensions(width, height, opts);
+    let first = DynamicImage::ImageRgba8(first.buffer().clone());
+    let filter = resolve_filter(&first, opts);
+
+    let mut encoded = vec![];
+    let (padded_width, padded_height) = padded_dimensions((nwidth, nheight), opts);
+    let mut encoder = png::Encoder::new(&mut encoded, padded_width, padded_height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    quantize::set_quality(&mut encoder, opts.compression, opts.row_filter);
+    encoder.set_animated(frames.len() as u32, plays(path)?)?;
+    let mut writer = encoder.write_header()?;
+    let exponent = gamma_exponent(&chunks::read(path)?, opts);
+    for frame in frames {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let (numer, denom) = delay_fraction(numer as f64 / denom as f64);
+        writer.set_frame_delay(numer, denom)?;
+        let mut frame = DynamicImage::ImageRgba8(frame.into_buffer());
+        if let Some(exponent) = exponent {
+            correct_gamma(&mut frame, exponent);
+        }
+        let resized = frame.resize_exact(nwidth, nheight, convert_filter(filter));
+        writer.write_image_data(pad(resized, opts).as_bytes())?;
+    }
+    writer.finish()?;
+
+    let temp_path = NamedTempFile::new_in(temp_dir)?;
+    (&temp_path).write_all(&encoded)?;
+    Ok(Candidate {
+        encoded: temp_path,
+        size: encoded.len() as u64,
+        color: ColorType::Rgba8,
+        dimensions: (nwidth, nheight),
+        filter,
+    })
+}
@@ -0,0 +1,366 @@
+use std::{fs, io::{self, Read, Write}, path::Path};
+
+use flate2::{read::{DeflateDecoder, GzDecoder}, write::GzEncoder};
+
+const ZIP_LOCAL: u32 = 0x0403_4b50;
+const ZIP_CENTRAL: u32 = 0x0201_4b50;
+const ZIP_END: u32 = 0x0605_4b50;
+/// Zip compression methods
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+/// Zip flags for an encrypted entry, and for sizes given after the data instead of in the local header
+const ENCRYPTED: u16 = 1;
+const DATA_DESCRIPTOR: u16 = 1 << 3;
+
+const BLOCK: usize = 512;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> io::Result<u16> {
+    bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]])).ok_or_else(|| invalid("zip is truncated"))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> io::Result<u32> {
+    bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(|| invalid("zip is truncated"))
+}
+
+fn slice(bytes: &[u8], at: usize, len: usize) -> io::Result<&[u8]> {
+    bytes.get(at..at + len).ok_or_else(|| invalid("archive is truncated"))
+}
+
+/// A zip entry's central directory record, minus the offset and lengths that are worked out again on writing
+struct ZipRecord {
+    version_made_by: u16,
+    version_needed: u16,
+    flags: u16,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u32,
+    name: Vec<u8>,
+    local_extra: Vec<u8>,
+    central_extra: Vec<u8>,
+    comment: Vec<u8>,
+    internal_attributes: u16,
+    external_attributes: u32,
+}
+
+/// How an entry is stored, kept as read so entries that aren't touched are written back byte for byte
+enum Raw {
+    /// The record and the entry's data, still compressed
+    Zip(ZipRecord, Vec<u8>),
+    /// The header block and the entry's data
+    Tar(Box<[u8; BLOCK]>, Vec<u8>),
+}
+
+/// A file, directory or other record in an archive
+pub struct Entry {
+    /// Path of the entry inside the archive
+    pub name: String,
+    raw: Raw,
+}
+
+impl Entry {
+    /// Whether the entry is a regular file named like a png
+    pub fn is_png(&self) -> bool {
+        let is_file = match &self.raw {
+            Raw::Zip(..) => !self.name.ends_with('/'),
+            Raw::Tar(header, _) => matches!(header[156], b'0' | 0 | b'7'),
+        };
+        is_file && self.name.to_ascii_lowercase().ends_with(".png")
+    }
+
+    /// The entry's contents, decompressed
+    pub fn contents(&self) -> io::Result<Vec<u8>> {
+        match &self.raw {
+            Raw::Tar(_, data) => Ok(data.clone()),
+            Raw::Zip(record, _) if record.flags & ENCRYPTED != 0 => Err(io::Error::new(io::ErrorKind::Unsupported, "zip entry is encrypted")),
+            Raw::Zip(record, data) => {
+                let contents = match record.method {
+                    STORED => data.clone(),
+                    DEFLATED => {
+                        let mut contents = Vec::with_capacity(record.size as usize);
+                        DeflateDecoder::new(&data[..]).read_to_end(&mut contents)?;
+                        contents
+                    },
+                    method => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("zip compression method {} isn't supported", method))),
+                };
+                if crc32fast::hash(&contents) != record.crc {
+                    return Err(invalid("zip entry doesn't match its checksum"));
+                }
+                Ok(contents)
+            },
+        }
+    }
+
+    /// Swaps the entry's contents for `contents`. Zip entries are stored as they are, since pngs are compressed already
+    pub fn replace(&mut self, contents: Vec<u8>) {
+        match &mut self.raw {
+            Raw::Zip(record, data) => {
+                record.method = STORED;
+                record.flags &= !DATA_DESCRIPTOR;
+                record.crc = crc32fast::hash(&contents);
+                record.size = contents.len() as u32;
+                *data = contents;
+            },
+            Raw::Tar(header, data) => {
+                header[124..136].copy_from_slice(format!("{:011o}\0", contents.len()).as_bytes());
+                set_tar_checksum(header);
+                *data = contents;
+            },
+        }
+    }
+}
+
+/// Container formats read and written, going by the file extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Kind {
+    fn of(path: &Path) -> io::Result<Kind> {
+        let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_ascii_lowercase());
+        if name.ends_with(".zip") {
+            Ok(Kind::Zip)
+        } else if name.ends_with(".tar") {
+            Ok(Kind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Kind::TarGz)
+        } else {
+            Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} isn't a .zip, .tar, .tar.gz or .tgz", path.display())))
+        }
+    }
+}
+
+/// The entries of a zip or tar, in order
+pub struct Archive {
+    pub entries: Vec<Entry>,
+    /// The zip's comment
+    comment: Vec<u8>,
+    kind: Kind,
+}
+
+impl Archive {
+    pub fn read(path: &Path) -> io::Result<Archive> {
+        let kind = Kind::of(path)?;
+        let bytes = fs::read(path)?;
+        match kind {
+            Kind::Zip => read_zip(&bytes),
+            Kind::Tar => Ok(Archive { entries: read_tar(&bytes)?, comment: vec![], kind }),
+            Kind::TarGz => {
+                let mut tar = vec![];
+                GzDecoder::new(&bytes[..]).read_to_end(&mut tar)?;
+                Ok(Archive { entries: read_tar(&tar)?, comment: vec![], kind })
+            },
+        }
+    }
+
+    /// Checks the archive can be written to `path`, going by its extension, and returns the format it'd be written in.
+    /// Zips can only be written as zips and tars as tars, gzipped or not
+    pub fn check_writable(&self, path: &Path) -> io::Result<Kind> {
+        let kind = Kind::of(path)?;
+        if (kind == Kind::Zip) != (self.kind == Kind::Zip) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("zips and tars can't be converted into each other, so {} has to be a {}", path.display(), if self.kind == Kind::Zip { ".zip" } else { ".tar, .tar.gz or .tgz" })));
+        }
+        Ok(kind)
+    }
+
+    /// Writes the archive to `path`, in the format its extension calls for
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let bytes = match self.check_writable(path)? {
+            Kind::Zip => self.zip(),
+            Kind::Tar => self.tar(),
+            Kind::TarGz => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::best());
+                encoder.write_all(&self.tar())?;
+                encoder.finish()?
+            },
+        };
+        fs::write(path, bytes)
+    }
+
+    fn zip(&self) -> Vec<u8> {
+        let (mut out, mut central) = (vec![], vec![]);
+        for entry in &self.entries {
+            let Raw::Zip(record, data) = &entry.raw else { continue };
+            let offset = out.len() as u32;
+            // Sizes are always known up front here, so a data descriptor never follows
+            let flags = record.flags & !DATA_DESCRIPTOR;
+            out.extend_from_slice(&ZIP_LOCAL.to_le_bytes());
+            for field in [record.version_needed, flags, record.method, record.time, record.date] {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [record.crc, data.len() as u32, record.size] {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+            out.extend_from_slice(&(record.name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(record.local_extra.len() as u16).to_le_bytes());
+            out.extend_from_slice(&record.name);
+            out.extend_from_slice(&record.local_extra);
+            out.extend_from_slice(data);
+
+            central.extend_from_slice(&ZIP_CENTRAL.to_le_bytes());
+            for field in [record.version_made_by, record.version_needed, flags, record.method, record.time, record.date] {
+                central.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [record.crc, data.len() as u32, record.size] {
+                central.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [record.name.len() as u16, record.central_extra.len() as u16, record.comment.len() as u16, 0, record.internal_attributes] {
+                central.extend_from_slice(&field.to_le_bytes());
+            }
+            central.extend_from_slice(&record.external_attributes.to_le_bytes());
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(&record.name);
+            central.extend_from_slice(&record.central_extra);
+            central.extend_from_slice(&record.comment);
+        }
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&ZIP_END.to_le_bytes());
+        for field in [0, 0, self.entries.len() as u16, self.entries.len() as u16] {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&central_offset.to_le_bytes());
+        out.extend_from_slice(&(self.comment.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.comment);
+        out
+    }
+
+    fn tar(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for entry in &self.entries {
+            let Raw::Tar(header, data) = &entry.raw else { continue };
+            out.extend_from_slice(&header[..]);
+            out.extend_from_slice(data);
+            out.resize(out.len().next_multiple_of(BLOCK), 0);
+        }
+        // Two empty blocks mark the end
+        out.resize(out.len() + 2 * BLOCK, 0);
+        out
+    }
+}
+
+/// Reads a zip through its central directory. Zip64 and archives split over several disks aren't supported
+fn read_zip(bytes: &[u8]) -> io::Result<Archive> {
+    // The end record sits at the very end, followed only by a comment of up to 64k
+    let search_from = bytes.len().saturating_sub(22 + u16::MAX as usize);
+    let end = (search_from..bytes.len().saturating_sub(21)).rev()
+        .find(|&at| u32_at(bytes, at).ok() == Some(ZIP_END))
+        .ok_or_else(|| invalid("not a zip, or its end record is missing"))?;
+    let count = u16_at(bytes, end + 10)?;
+    let central_offset = u32_at(bytes, end + 16)?;
+    if u16_at(bytes, end + 4)? != 0 || count == u16::MAX || central_offset == u32::MAX {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "zip64 and multi-disk zips aren't supported"));
+    }
+    let comment = slice(bytes, end + 22, u16_at(bytes, end + 20)? as usize)?.to_vec();
+
+    let mut entries = vec![];
+    let mut at = central_offset as usize;
+    for _ in 0..count {
+        if u32_at(bytes, at)? != ZIP_CENTRAL {
+            return Err(invalid("zip central directory is corrupt"));
+        }
+        let compressed_size = u32_at(bytes, at + 20)?;
+        let (name_len, extra_len, comment_len) = (u16_at(bytes, at + 28)? as usize, u16_at(bytes, at + 30)? as usize, u16_at(bytes, at + 32)? as usize);
+        let local = u32_at(bytes, at + 42)? as usize;
+        let size = u32_at(bytes, at + 24)?;
+        if [compressed_size, size, local as u32].contains(&u32::MAX) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "zip64 entries aren't supported"));
+        }
+        let name = slice(bytes, at + 46, name_len)?.to_vec();
+        if u32_at(bytes, local)? != ZIP_LOCAL {
+            return Err(invalid(format!("zip entry {} is corrupt", String::from_utf8_lossy(&name))));
+        }
+        let local_extra_len = u16_at(bytes, local + 28)? as usize;
+        let local_extra = slice(bytes, local + 30 + u16_at(bytes, local + 26)? as usize, local_extra_len)?.to_vec();
+        let data_start = local + 30 + u16_at(bytes, local + 26)? as usize + local_extra_len;
+        let record = ZipRecord {
+            version_made_by: u16_at(bytes, at + 4)?,
+            version_needed: u16_at(bytes, at + 6)?,
+            flags: u16_at(bytes, at + 8)?,
+            method: u16_at(bytes, at + 10)?,
+            time: u16_at(bytes, at + 12)?,
+            date: u16_at(bytes, at + 14)?,
+            crc: u32_at(bytes, at + 16)?,
+            size,
+            local_extra,
+            central_extra: slice(bytes, at + 46 + name_len, extra_len)?.to_vec(),
+            comment: slice(bytes, at + 46 + name_len + extra_len, comment_len)?.to_vec(),
+            internal_attributes: u16_at(bytes, at + 36)?,
+            external_attributes: u32_at(bytes, at + 38)?,
+            name,
+        };
+        entries.push(Entry {
+            name: String::from_utf8_lossy(&record.name).into_owned(),
+            raw: Raw::Zip(record, slice(bytes, data_start, compressed_size as usize)?.to_vec()),
+        });
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(Archive { entries, comment, kind: Kind::Zip })
+}
+
+/// A NUL terminated string field of a tar header
+fn tar_field(field: &[u8]) -> String {
+    let end = field.iter().position(|byte| *byte == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// An octal number field of a tar header
+fn tar_number(field: &[u8]) -> io::Result<usize> {
+    let digits = tar_field(field);
+    let digits = digits.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, 8).map_err(|_| invalid("tar header is corrupt"))
+}
+
+fn set_tar_checksum(header: &mut [u8; BLOCK]) {
+    // The checksum is worked out with its own field as spaces
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+}
+
+/// Reads the entries of an uncompressed tar. GNU long names and pax paths are followed so pngs are found by their
+/// full path, and kept as entries of their own so they're written back unchanged
+fn read_tar(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut long_name = None;
+    let mut at = 0;
+    while at + BLOCK <= bytes.len() {
+        let header: Box<[u8; BLOCK]> = Box::new(bytes[at..at + BLOCK].try_into().unwrap());
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let size = tar_number(&header[124..136])?;
+        let data = slice(bytes, at + BLOCK, size)?.to_vec();
+        at += BLOCK + size.next_multiple_of(BLOCK);
+        let mut name = tar_field(&header[0..100]);
+        if &header[257..262] == b"ustar" {
+            let prefix = tar_field(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+        match header[156] {
+            b'L' => long_name = Some(tar_field(&data)),
+            b'x' => {
+                // Records are "<length> <key>=<value>\n"
+                let records = String::from_utf8_lossy(&data);
+                long_name = records.lines().filter_map(|record| record.split_once(' ')?.1.strip_prefix("path=")).next_back().map(str::to_string).or(long_name);
+            },
+            _ => name = long_name.take().unwrap_or(name),
+        }
+        entries.push(Entry { name, raw: Raw::Tar(header, data) });
+    }
+    Ok(entries)
+}
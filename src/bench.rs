@@ -0,0 +1,62 @@
+use std::{error::Error, fs, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use clap::ValueEnum;
+use log::warn;
+use png_squasher::{encode_image, load_and_preprocess, target_dimensions, Compression, CompressOptions, Filter};
+use rayon::{iter::{IntoParallelRefIterator, ParallelIterator}, ThreadPool};
+
+use crate::format_bytes;
+
+/// Size and time of one png encoded with each combination, in the order of `combinations`
+type Trial = Vec<(u64, Duration)>;
+
+/// Every resize filter paired with every compression level. Auto only ever picks one of the other filters
+fn combinations() -> Vec<(Filter, Compression)> {
+    Filter::value_variants().iter().filter(|filter| !matches!(filter, Filter::Auto))
+        .flat_map(|filter| Compression::value_variants().iter().map(|compression| (*filter, *compression)))
+        .collect()
+}
+
+/// Resizes and encodes `png` with each of `combinations`, timing them. Output goes to temp files and is thrown away
+fn trial(png: &Path, combinations: &[(Filter, Compression)], opts: &CompressOptions) -> Result<Trial, Box<dyn Error>> {
+    let image = load_and_preprocess(png, opts)?.swap_remove(0);
+    let (nwidth, nheight) = target_dimensions(image.width(), image.height(), opts);
+    let temp_dir = std::env::temp_dir();
+    combinations.iter().map(|(filter, compression)| {
+        let opts = CompressOptions { filter: *filter, auto_filter: false, compression: *compression, ..opts.clone() };
+        let start = Instant::now();
+        let encoded = encode_image(&image, nwidth, nheight, &temp_dir, &opts)?;
+        let elapsed = start.elapsed();
+        Ok((fs::metadata(encoded.path())?.len(), elapsed))
+    }).collect()
+}
+
+/// Encodes every png on `pool` with every filter and compression level, then prints the average size and time of
+/// each combination, smallest first. Nothing is overwritten
+pub fn report(pngs: &[PathBuf], opts: &CompressOptions, pool: &ThreadPool) {
+    let combinations = combinations();
+    let trials: Vec<(u64, Trial)> = pool.install(|| pngs.par_iter().filter_map(|png| {
+        let trial = trial(png, &combinations, opts).inspect_err(|e| warn!("{}: couldn't be benched: {}", png.display(), e)).ok()?;
+        Some((fs::metadata(png).map_or(0, |metadata| metadata.len()), trial))
+    }).collect());
+    if trials.is_empty() {
+        println!("No pngs to bench");
+        return;
+    }
+
+    let count = trials.len() as u64;
+    println!("Benched {} pngs, averaging {} each before", count, format_bytes(trials.iter().map(|(size, _)| size).sum::<u64>() / count));
+    let mut averages: Vec<(Filter, Compression, u64, Duration)> = combinations.iter().enumerate().map(|(i, (filter, compression))| {
+        let size = trials.iter().map(|(_, trial)| trial[i].0).sum::<u64>() / count;
+        let time = trials.iter().map(|(_, trial)| trial[i].1).sum::<Duration>() / count as u32;
+        (*filter, *compression, size, time)
+    }).collect();
+    averages.sort_by_key(|(_, _, size, time)| (*size, *time));
+    let name = |value: Option<clap::builder::PossibleValue>| value.map_or(String::new(), |value| value.get_name().to_string());
+    println!("  {:<12} {:<12} {:>10} {:>10}", "filter", "compression", "avg size", "avg time");
+    for (filter, compression, size, time) in &averages {
+        println!("  {:<12} {:<12} {:>10} {:>8.1}ms", name(filter.to_possible_value()), name(compression.to_possible_value()), format_bytes(*size), time.as_secs_f64() * 1000.0);
+    }
+    let (filter, compression, _, _) = averages[0];
+    println!("Smallest on average: --filter {} --compression {}", name(filter.to_possible_value()), name(compression.to_possible_value()));
+}
@@ -0,0 +1,37 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A weighted semaphore, e.g. over bytes of memory so the images being worked on at once fit under a cap
+pub struct Budget {
+    limit: u64,
+    used: Mutex<u64>,
+    freed: Condvar,
+}
+
+/// Bytes held from a `Budget`, handed back when dropped. It keeps the budget alive itself, so it can go to a thread
+/// that outlives whoever reserved it
+pub struct Reservation {
+    budget: Arc<Budget>,
+    bytes: u64,
+}
+
+impl Budget {
+    pub fn new(limit: u64) -> Budget {
+        Budget { limit, used: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    /// Blocks until `bytes` fit in what's left of the budget. Anything over the whole budget is clamped to it, so a
+    /// huge image still runs, just on its own
+    pub fn reserve(self: &Arc<Self>, bytes: u64) -> Reservation {
+        let bytes = bytes.min(self.limit);
+        let mut used = self.freed.wait_while(self.used.lock().unwrap(), |used| *used + bytes > self.limit).unwrap();
+        *used += bytes;
+        Reservation { budget: Arc::clone(self), bytes }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
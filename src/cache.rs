@@ -0,0 +1,90 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use png_squasher::json;
+
+/// What a png looked like after the last run that compressed it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Size of the input png
+    size: u64,
+    /// Modification time of the input png, in nanoseconds since the Unix epoch
+    mtime: u64,
+    /// FNV-1a hash of the output's contents
+    hash: u64,
+}
+
+/// 64 bit FNV-1a, which is plenty to tell files apart
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+impl Entry {
+    /// The entry for `input` as it is now, with the output that was written for it
+    pub fn of(input: &Path, output: &Path) -> io::Result<Entry> {
+        let metadata = fs::metadata(input)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+        Ok(Entry { size: metadata.len(), mtime, hash: fnv1a(&fs::read(output)?) })
+    }
+}
+
+/// A JSON manifest of the pngs compressed by earlier runs, so unchanged ones can be skipped
+pub struct Cache {
+    path: PathBuf,
+    /// Compression settings the entries were made with. A run with different settings starts from scratch
+    settings: String,
+    entries: HashMap<String, Entry>,
+}
+
+impl Cache {
+    /// Loads the manifest at `path`. A missing or unreadable manifest, or one written with other settings, is empty
+    pub fn load(path: PathBuf, settings: String) -> Cache {
+        let mut cache = Cache { path, settings, entries: HashMap::new() };
+        let Ok(text) = fs::read_to_string(&cache.path) else {
+            return cache;
+        };
+        let manifest = match json::parse(&text) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!("{}: isn't a valid cache manifest ({}), starting over", cache.path.display(), e);
+                return cache;
+            },
+        };
+        if manifest.get("settings").and_then(json::Value::as_str) != Some(cache.settings.as_str()) {
+            debug!("{}: written with different settings, starting over", cache.path.display());
+            return cache;
+        }
+        if let Some(json::Value::Object(files)) = manifest.get("files") {
+            cache.entries = files.iter().filter_map(|(png, entry)| {
+                let entry = Entry {
+                    size: entry.get("size")?.as_u64()?,
+                    mtime: entry.get("mtime")?.as_u64()?,
+                    hash: u64::from_str_radix(entry.get("hash")?.as_str()?, 16).ok()?,
+                };
+                Some((png.clone(), entry))
+            }).collect();
+        }
+        cache
+    }
+
+    /// True if `input` hasn't changed since it was recorded and `output` still holds what was written for it
+    pub fn is_fresh(&self, input: &Path, output: &Path) -> bool {
+        self.entries.get(input.to_string_lossy().as_ref()).is_some_and(|entry| Entry::of(input, output).is_ok_and(|current| current == *entry))
+    }
+
+    pub fn insert(&mut self, input: &Path, entry: Entry) {
+        self.entries.insert(input.to_string_lossy().into_owned(), entry);
+    }
+
+    /// Writes the manifest, dropping pngs that no longer exist
+    pub fn save(&self) -> io::Result<()> {
+        let mut pngs: Vec<_> = self.entries.iter().filter(|(png, _)| Path::new(png).exists()).collect();
+        pngs.sort_by_key(|(png, _)| *png);
+        let files: Vec<String> = pngs.iter().map(|(png, entry)| {
+            format!("    {}: {{\"size\": {}, \"mtime\": {}, \"hash\": \"{:016x}\"}}", json::string(png), entry.size, entry.mtime, entry.hash)
+        }).collect();
+        let manifest = format!("{{\n  \"settings\": {},\n  \"files\": {{\n{}\n  }}\n}}\n", json::string(&self.settings), files.join(",\n"));
+        fs::write(&self.path, manifest)
+    }
+}
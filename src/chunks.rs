@@ -0,0 +1,150 @@
+use std::{fs, io, path::Path};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Chunks holding textual metadata such as Title, Author and Copyright
+pub const TEXT: [[u8; 4]; 3] = [*b"tEXt", *b"zTXt", *b"iTXt"];
+
+/// Raw EXIF block, which holds the orientation among much else
+pub const EXIF: [u8; 4] = *b"eXIf";
+
+/// Embedded ICC color profile
+pub const ICC: [u8; 4] = *b"iCCP";
+
+/// Physical size of the pixels
+pub const PHYS: [u8; 4] = *b"pHYs";
+
+/// Gamma the image was encoded with
+pub const GAMA: [u8; 4] = *b"gAMA";
+
+/// Marks the image as sRGB, which overrides any gAMA
+pub const SRGB: [u8; 4] = *b"sRGB";
+
+/// pHYs unit for pixels per meter. The only other one, 0, just gives the pixels' aspect ratio
+const PER_METER: u8 = 1;
+
+/// A raw png chunk, without its length and CRC
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub kind: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// Splits a png into its chunks, stopping at IEND or the first truncated chunk. Anything that isn't a png has no chunks
+pub fn parse(bytes: &[u8]) -> Vec<Chunk> {
+    let mut chunks = vec![];
+    let Some(mut rest) = bytes.strip_prefix(&SIGNATURE) else {
+        return chunks;
+    };
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() - 12 < len {
+            break;
+        }
+        let kind = [rest[4], rest[5], rest[6], rest[7]];
+        chunks.push(Chunk { kind, data: rest[8..8 + len].to_vec() });
+        rest = &rest[12 + len..];
+        if &kind == b"IEND" {
+            break;
+        }
+    }
+    chunks
+}
+
+pub fn read(path: &Path) -> io::Result<Vec<Chunk>> {
+    Ok(parse(&fs::read(path)?))
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&chunk.kind);
+    crc.update(&chunk.data);
+    out.extend_from_slice(&(chunk.data.len() as u32).to_be_bytes());
+    out.extend_from_slice(&chunk.kind);
+    out.extend_from_slice(&chunk.data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Puts `chunks` back together into a png
+pub fn serialize(chunks: &[Chunk]) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    for chunk in chunks {
+        write_chunk(&mut out, chunk);
+    }
+    out
+}
+
+/// Critical chunks, plus tRNS since dropping it would change the pixels of paletted and color-keyed pngs, and the
+/// chunks that make up an APNG's animation
+pub fn is_essential(chunk: &Chunk) -> bool {
+    chunk.kind[0].is_ascii_uppercase() || [*b"tRNS", *b"acTL", *b"fcTL", *b"fdAT"].contains(&chunk.kind)
+}
+
+/// Rebuilds the png in `bytes`, dropping chunks that fail `keep` and inserting `extra` right after IHDR, which is a
+/// valid spot for any ancillary chunk
+pub fn rewrite_bytes(bytes: &[u8], keep: impl Fn(&Chunk) -> bool, extra: &[Chunk]) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    for chunk in parse(bytes).iter().filter(|chunk| keep(chunk)) {
+        write_chunk(&mut out, chunk);
+        if &chunk.kind == b"IHDR" {
+            for chunk in extra {
+                write_chunk(&mut out, chunk);
+            }
+        }
+    }
+    out
+}
+
+/// `rewrite_bytes` for the png at `path`, in place. Returns the new file size
+pub fn rewrite(path: &Path, keep: impl Fn(&Chunk) -> bool, extra: &[Chunk]) -> io::Result<u64> {
+    let out = rewrite_bytes(&fs::read(path)?, keep, extra);
+    fs::write(path, &out)?;
+    Ok(out.len() as u64)
+}
+
+fn phys(x: u32, y: u32, unit: u8) -> Chunk {
+    let mut data = Vec::with_capacity(9);
+    data.extend_from_slice(&x.to_be_bytes());
+    data.extend_from_slice(&y.to_be_bytes());
+    data.push(unit);
+    Chunk { kind: PHYS, data }
+}
+
+/// A pHYs chunk for `dpi` dots per inch on both axes
+pub fn phys_for_dpi(dpi: u32) -> Chunk {
+    let per_meter = (dpi as f64 / 0.0254).round() as u32;
+    phys(per_meter, per_meter, PER_METER)
+}
+
+/// The pHYs `chunk` of an image `from` in size, redone for the image resized to `to` so it keeps its physical size.
+/// Each axis is scaled on its own, since --power-of-two can stretch one more than the other. `chunk` has to match the
+/// way `from` is turned, see `turn_phys`. None if `chunk` is malformed
+pub fn rescale_phys(chunk: &Chunk, from: (u32, u32), to: (u32, u32)) -> Option<Chunk> {
+    let data: &[u8; 9] = chunk.data.as_slice().try_into().ok()?;
+    let scale = |bytes: &[u8], from: u32, to: u32| {
+        ((u32::from_be_bytes(bytes.try_into().unwrap()) as f64 * to as f64 / from.max(1) as f64).round() as u32).max(1)
+    };
+    Some(phys(scale(&data[0..4], from.0, to.0), scale(&data[4..8], from.1, to.1), data[8]))
+}
+
+/// The pHYs `chunk` with its axes swapped, for an image turned a quarter of the way round. None if `chunk` is malformed
+pub fn turn_phys(chunk: &Chunk) -> Option<Chunk> {
+    let data: &[u8; 9] = chunk.data.as_slice().try_into().ok()?;
+    Some(Chunk { kind: PHYS, data: [&data[4..8], &data[0..4], &data[8..]].concat() })
+}
+
+/// The encoding gamma `chunks` declare, e.g. 0.45455 for an image meant for a 2.2 display. None if they don't
+/// declare one, or declare sRGB
+pub fn gamma(chunks: &[Chunk]) -> Option<f64> {
+    if chunks.iter().any(|chunk| chunk.kind == SRGB) {
+        return None;
+    }
+    let data: &[u8; 4] = chunks.iter().find(|chunk| chunk.kind == GAMA)?.data.as_slice().try_into().ok()?;
+    Some(u32::from_be_bytes(*data) as f64 / 100_000.0).filter(|gamma| *gamma > 0.0)
+}
+
+/// A gAMA chunk for pixels meant for a display with gamma `display_gamma`
+pub fn gama_for(display_gamma: f64) -> Chunk {
+    let gamma = (100_000.0 / display_gamma).round() as u32;
+    Chunk { kind: GAMA, data: gamma.to_be_bytes().to_vec() }
+}
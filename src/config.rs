@@ -0,0 +1,212 @@
+use std::{any::TypeId, env, error::Error, ffi::OsString, fs, path::{self, Path, PathBuf}};
+
+use clap::{parser::ValueSource, ArgAction, CommandFactory, FromArgMatches};
+
+use crate::Args;
+
+/// Name of the config file looked for in the search directory and its ancestors
+const FILE_NAME: &str = "squasher.toml";
+
+/// A value from the config file, in the subset of TOML it understands
+#[derive(Debug)]
+enum Value {
+    Bool(bool),
+    /// Strings, and numbers kept as written since clap parses them from text anyway
+    Scalar(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn to_arg(&self) -> Option<String> {
+        match self {
+            Value::Bool(b) => Some(b.to_string()),
+            Value::Scalar(s) => Some(s.clone()),
+            Value::Array(_) => None,
+        }
+    }
+}
+
+/// Parses one value, returning it and whatever follows it on the line
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    let text = text.trim_start();
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut s = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::Scalar(s), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, c)) => s.push(c),
+                    None => break,
+                },
+                c => s.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    } else if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        Ok((Value::Scalar(rest[..end].to_string()), &rest[end + 1..]))
+    } else if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            let after = after.trim_start();
+            rest = match after.strip_prefix(',') {
+                Some(after) => after,
+                None if after.starts_with(']') => after,
+                None => return Err("expected `,` or `]` in array".to_string()),
+            };
+        }
+    } else {
+        let end = text.find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace()).unwrap_or(text.len());
+        let (word, rest) = text.split_at(end);
+        match word {
+            "" => Err("missing value".to_string()),
+            "true" => Ok((Value::Bool(true), rest)),
+            "false" => Ok((Value::Bool(false), rest)),
+            _ if word.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') => Ok((Value::Scalar(word.replace('_', "")), rest)),
+            _ => Err(format!("`{}` isn't a string, number, boolean or array", word)),
+        }
+    }
+}
+
+/// Parses the flat `key = value` subset of TOML: strings, numbers, booleans and arrays, which may span lines. Tables
+/// aren't supported, since every setting lives at the top level
+fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut entries = vec![];
+    let mut lines = text.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| format!("line {}: expected `key = value`", number + 1))?;
+        let key = key.trim().trim_matches('"').to_string();
+        // An array that doesn't close on this line carries on over the next ones
+        let mut value = value.to_string();
+        while value.matches('[').count() > value.matches(']').count() && let Some((_, next)) = lines.next() {
+            value.push(' ');
+            value.push_str(next.split('#').next().unwrap_or(""));
+        }
+        let (value, rest) = parse_value(&value).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(format!("line {}: unexpected `{}` after the value", number + 1, rest));
+        }
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+/// The squasher.toml in `dir` or the closest of its ancestors that has one
+fn discover(dir: &Path) -> Option<PathBuf> {
+    path::absolute(dir).ok()?.ancestors().map(|dir| dir.join(FILE_NAME)).find(|config| config.is_file())
+}
+
+/// True for flags that take a path, which in a config file are relative to the file rather than to wherever the
+/// command happens to run. --files-from takes a path too, unless it's `-` for stdin
+fn is_path(arg: &clap::Arg) -> bool {
+    arg.get_value_parser().type_id() == TypeId::of::<PathBuf>() || arg.get_id() == "files_from"
+}
+
+/// `value` resolved against the directory holding `config`, unless it's absolute or stdin
+fn relative_to(config: &Path, value: String) -> OsString {
+    match config.parent() {
+        Some(dir) if value != "-" && Path::new(&value).is_relative() => dir.join(value).into_os_string(),
+        _ => value.into(),
+    }
+}
+
+/// Parses the command line, with settings from --config, or a discovered squasher.toml, filling in any flag that
+/// wasn't given. The config's entries are turned into flags ahead of the real ones, so clap validates them just the
+/// same. Ones that clash with a flag that was given are left out, so the command line wins. Returns the args and
+/// warnings about the config to log once logging is set up
+pub fn args() -> Result<(Args, Vec<String>), Box<dyn Error>> {
+    args_from(env::args_os().collect())
+}
+
+/// `args` for the command line `cli`
+fn args_from(cli: Vec<OsString>) -> Result<(Args, Vec<String>), Box<dyn Error>> {
+    let matches = Args::command().get_matches_from(&cli);
+    let explicit = matches.get_one::<PathBuf>("config").cloned();
+    let root = matches.get_many::<PathBuf>("dir").and_then(|mut dirs| dirs.next().cloned()).unwrap_or_else(|| PathBuf::from("."));
+    let Some(config) = explicit.or_else(|| discover(&root)) else {
+        return Ok((Args::from_arg_matches(&matches)?, vec![]));
+    };
+    let text = fs::read_to_string(&config).map_err(|e| format!("{}: couldn't be read: {}", config.display(), e))?;
+    let entries = parse(&text).map_err(|e| format!("{}: {}", config.display(), e))?;
+
+    let command = Args::command();
+    let given: Vec<&clap::Arg> = command.get_arguments().filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)).collect();
+    // Conflicts are often only declared on one of the two args, so look both ways
+    let clashes = |arg: &clap::Arg| given.iter().any(|other| {
+        command.get_arg_conflicts_with(arg).iter().any(|conflict| conflict.get_id() == other.get_id())
+            || command.get_arg_conflicts_with(other).iter().any(|conflict| conflict.get_id() == arg.get_id())
+    });
+    let mut warnings = vec![];
+    let mut from_config: Vec<OsString> = vec![];
+    for (key, value) in entries {
+        let id = key.replace('-', "_");
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id.as_str() && arg.get_long().is_some() && id != "config") else {
+            warnings.push(format!("{}: ignoring unknown setting `{}`", config.display(), key));
+            continue;
+        };
+        if matches.value_source(&id) == Some(ValueSource::CommandLine) || clashes(arg) {
+            continue;
+        }
+        let flag = format!("--{}", arg.get_long().unwrap());
+        let items = match value {
+            Value::Array(items) => items,
+            value => vec![value],
+        };
+        for item in items {
+            match (arg.get_action(), item) {
+                (ArgAction::SetTrue, Value::Bool(true)) => from_config.push(flag.clone().into()),
+                (ArgAction::SetTrue, Value::Bool(false)) => {},
+                (ArgAction::Count, Value::Scalar(count)) => match count.parse::<usize>() {
+                    Ok(count) => from_config.extend(std::iter::repeat_n(OsString::from(&flag), count)),
+                    Err(_) => warnings.push(format!("{}: `{}` should be a count, ignoring it", config.display(), key)),
+                },
+                (_, item) => match item.to_arg() {
+                    Some(value) if arg.get_action().takes_values() => {
+                        let value = if is_path(arg) { relative_to(&config, value) } else { value.into() };
+                        let mut flag_value = OsString::from(format!("{}=", flag));
+                        flag_value.push(value);
+                        from_config.push(flag_value);
+                    },
+                    _ => warnings.push(format!("{}: `{}` can't be set to {:?}, ignoring it", config.display(), key, item)),
+                },
+            }
+        }
+    }
+    let merged = cli.iter().take(1).chain(&from_config).chain(cli.iter().skip(1));
+    let matches = Args::command().try_get_matches_from(merged).map_err(|e| {
+        // Just clap's message, without its usage hints, which are about the command line
+        let message = e.render().to_string();
+        format!("{}: {}", config.display(), message.trim_start_matches("error: ").lines().next().unwrap_or_default())
+    })?;
+    Ok((Args::from_arg_matches(&matches)?, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_beats_clashing_config() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(FILE_NAME), "percent = 50\nquarantine = \"broken\"\n").unwrap();
+        let cli = ["png_squasher", "--x-max", "100", "--dir"].map(OsString::from).into_iter().chain([dir.path().into()]).collect();
+        let (args, warnings) = args_from(cli).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!((args.percent, args.x_max), (None, Some(100)));
+        assert_eq!(args.quarantine, Some(dir.path().join("broken")));
+    }
+}
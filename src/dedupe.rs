@@ -0,0 +1,66 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}};
+
+use log::{debug, warn};
+
+use crate::cache::fnv1a;
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Replaces `duplicate` with a hard link to `original`. The link is made next to it and renamed over it, so
+/// `duplicate` never goes missing
+fn link(original: &Path, duplicate: &Path) -> io::Result<()> {
+    let mut temp = duplicate.as_os_str().to_owned();
+    temp.push(".dedupe");
+    fs::hard_link(original, &temp)?;
+    fs::rename(&temp, duplicate).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Replaces every file in `paths` that's byte for byte the same as an earlier one with a hard link to that one.
+/// Files that can't be linked, e.g. on filesystems without hard links, are left as copies. Returns how many files
+/// were linked and how many bytes that freed
+pub fn link_duplicates(paths: &[PathBuf]) -> (usize, u64) {
+    let mut groups: HashMap<(u64, u64), Vec<&Path>> = HashMap::new();
+    for path in paths {
+        if let Ok(contents) = fs::read(path) {
+            groups.entry((contents.len() as u64, fnv1a(&contents))).or_default().push(path);
+        }
+    }
+    let (mut linked, mut freed) = (0, 0);
+    for ((size, _), group) in groups {
+        let Some((original, duplicates)) = group.split_first() else {
+            continue;
+        };
+        let Ok(contents) = fs::read(original) else {
+            continue;
+        };
+        for duplicate in duplicates {
+            // Matching hashes are checked against the actual bytes, since a collision would lose data
+            if is_same_file(original, duplicate) || fs::read(duplicate).ok().as_ref() != Some(&contents) {
+                continue;
+            }
+            match link(original, duplicate) {
+                Ok(()) => {
+                    debug!("{}: hard linked to {}", duplicate.display(), original.display());
+                    linked += 1;
+                    freed += size;
+                },
+                Err(e) => warn!("{}: couldn't be hard linked to {}, leaving it as a copy: {}", duplicate.display(), original.display(), e),
+            }
+        }
+    }
+    (linked, freed)
+}
@@ -0,0 +1,195 @@
+use std::{collections::{HashSet, VecDeque}, fs, io::Read, path::{Path, PathBuf}};
+
+use log::warn;
+
+use crate::{gitignore::{self, Gitignore}, glob};
+
+/// Settings that control which files `find_png_paths` picks up
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    /// Globs matched against the path relative to the scan root. Matching directories are not descended into
+    pub exclude: Vec<String>,
+    /// Globs matched against the path relative to the scan root. When any are given, only matching files are picked up
+    pub include: Vec<String>,
+    /// Skip anything ignored by .gitignore files in or above the scanned directories
+    pub respect_gitignore: bool,
+    /// How many directory levels below the root to descend into. 0 only looks at the root itself
+    pub max_depth: Option<usize>,
+    /// Descend into symlinked directories. Each directory is still only visited once
+    pub follow_symlinks: bool,
+    /// Also pick up files without a matching extension if their contents are one of `input_formats`
+    pub detect_content: bool,
+    /// Image formats to pick up. Empty means just pngs
+    pub input_formats: Vec<InputFormat>,
+}
+
+impl ScanOptions {
+    fn formats(&self) -> &[InputFormat] {
+        if self.input_formats.is_empty() { &[InputFormat::Png] } else { &self.input_formats }
+    }
+
+    /// True if the file at `path` is in one of `input_formats`, going by its contents
+    pub fn accepts(&self, path: &Path) -> bool {
+        InputFormat::sniff(path).is_some_and(|format| self.formats().contains(&format))
+    }
+}
+
+/// Image formats that can be read. Whatever the input, the output is encoded as `CompressOptions::format`
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Png,
+    Gif,
+    Bmp,
+}
+
+impl InputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            InputFormat::Png => "png",
+            InputFormat::Gif => "gif",
+            InputFormat::Bmp => "bmp",
+        }
+    }
+
+    fn matches_signature(self, header: &[u8]) -> bool {
+        match self {
+            InputFormat::Png => header.starts_with(&PNG_SIGNATURE),
+            InputFormat::Gif => header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a"),
+            InputFormat::Bmp => header.starts_with(b"BM"),
+        }
+    }
+
+    /// The format of the file at `path`, going by its first few bytes rather than its extension
+    pub fn sniff(path: &Path) -> Option<InputFormat> {
+        let mut header = Vec::with_capacity(8);
+        fs::File::open(path).and_then(|file| file.take(8).read_to_end(&mut header)).ok()?;
+        [InputFormat::Png, InputFormat::Gif, InputFormat::Bmp].into_iter().find(|format| format.matches_signature(&header))
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// True if the file at `path` starts with the png signature
+pub fn has_png_signature(path: &Path) -> bool {
+    let mut signature = [0_u8; 8];
+    fs::File::open(path).and_then(|mut file| file.read_exact(&mut signature)).is_ok() && signature == PNG_SIGNATURE
+}
+
+/// Inputs are recognised by their signature rather than trusting the extension
+fn is_input(path: &Path, scan: &ScanOptions) -> bool {
+    let by_extension = scan.formats().iter().copied().find(|format| {
+        path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case(format.extension()))
+    });
+    let candidate = by_extension.is_some() || (scan.detect_content && path.is_file());
+    if !candidate {
+        return false;
+    }
+    let sniffed = InputFormat::sniff(path);
+    if let Some(format) = by_extension && sniffed != Some(format) {
+        warn!("{}: has a .{} extension but isn't a {}, skipping", path.display(), format.extension(), format.extension());
+        return false;
+    }
+    sniffed.is_some_and(|format| scan.formats().contains(&format))
+}
+
+fn relative_path(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+fn is_excluded(relative: &str, is_dir: bool, scan: &ScanOptions, gitignores: &[Gitignore]) -> bool {
+    if scan.respect_gitignore && (relative == ".git" || gitignore::is_ignored(gitignores, relative, is_dir)) {
+        return true;
+    }
+    scan.exclude.iter().any(|pattern| {
+        glob::matches(pattern, relative) || (is_dir && glob::matches(pattern, &format!("{}/", relative)))
+    })
+}
+
+fn is_included(relative: &str, scan: &ScanOptions) -> bool {
+    scan.include.is_empty() || scan.include.iter().any(|pattern| glob::matches(pattern, relative))
+}
+
+/// A directory waiting to be searched
+struct Pending {
+    dir: PathBuf,
+    depth: usize,
+    /// How many of `Walk::gitignores` belong to the directory's ancestors
+    gitignores: usize,
+}
+
+/// Depth-first png search that works off its own stack, so deep trees can't overflow the real one. Each directory is
+/// read as the iterator reaches it, and its pngs are handed out before anything below it is looked at
+pub struct Walk<'a> {
+    root: &'a Path,
+    scan: &'a ScanOptions,
+    gitignores: Vec<Gitignore>,
+    /// Canonical paths of every directory entered so far, so symlink loops can't recurse forever
+    visited: HashSet<PathBuf>,
+    pending: Vec<Pending>,
+    /// Pngs found in the last directory read that haven't been handed out yet
+    found: VecDeque<PathBuf>,
+}
+
+/// Searches `base` for pngs, yielding their paths joined onto `base` as the search reaches them
+pub fn find_png_paths<'a>(base: &'a Path, scan: &'a ScanOptions) -> Walk<'a> {
+    let gitignores = if scan.respect_gitignore { gitignore::ancestors(base) } else { vec![] };
+    Walk {
+        root: base,
+        scan,
+        pending: vec![Pending { dir: base.to_path_buf(), depth: 0, gitignores: gitignores.len() }],
+        gitignores,
+        visited: HashSet::new(),
+        found: VecDeque::new(),
+    }
+}
+
+impl Walk<'_> {
+    /// Queues up the pngs directly in `dir` and pushes its subdirectories to be searched next
+    fn read(&mut self, Pending { dir, depth, gitignores }: Pending) {
+        // Anything left over belongs to a directory we've finished with
+        self.gitignores.truncate(gitignores);
+        if let Ok(canonical) = fs::canonicalize(&dir) && !self.visited.insert(canonical) {
+            return;
+        }
+        let Ok(read_dir) = fs::read_dir(&dir) else {
+            return;
+        };
+        let (root, scan) = (self.root, self.scan);
+        if scan.respect_gitignore && let Some(gitignore) = Gitignore::load(&dir, relative_path(root, &dir), String::new()) {
+            self.gitignores.push(gitignore);
+        }
+
+        let entries: Vec<PathBuf> = read_dir.filter_map(Result::ok).map(|entry| entry.path()).collect();
+        self.found.extend(entries.iter().filter(|entry| {
+            let relative = relative_path(root, entry);
+            !is_excluded(&relative, false, scan, &self.gitignores) && is_included(&relative, scan) && is_input(entry, scan)
+        }).cloned());
+
+        if scan.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+            return;
+        }
+        let dirs: Vec<PathBuf> = entries.into_iter().filter(|entry| {
+            entry.is_dir()
+                && (scan.follow_symlinks || !fs::symlink_metadata(entry).is_ok_and(|metadata| metadata.is_symlink()))
+                && !is_excluded(&relative_path(root, entry), true, scan, &self.gitignores)
+        }).collect();
+        // Reversed, so they come off the stack in the order they were listed
+        let gitignores = self.gitignores.len();
+        self.pending.extend(dirs.into_iter().rev().map(|dir| Pending { dir, depth: depth + 1, gitignores }));
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            if let Some(png) = self.found.pop_front() {
+                return Some(png);
+            }
+            let pending = self.pending.pop()?;
+            self.read(pending);
+        }
+    }
+}
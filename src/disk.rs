@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use log::debug;
+
+/// Workers used by default when pngs live on a spinning disk, where any more just make the heads seek back and forth
+pub const HDD_JOBS: usize = 2;
+
+/// Kind of storage the pngs are read from and written to
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DiskType {
+    /// Ask the operating system, treating anything it can't tell as an ssd
+    #[default]
+    Auto,
+    Hdd,
+    Ssd,
+}
+
+/// Whether the disk holding `path` spins, going by sysfs. None if it can't be told, e.g. for tmpfs or network mounts
+#[cfg(target_os = "linux")]
+fn is_rotational(path: &Path) -> Option<bool> {
+    use std::{fs, os::unix::fs::MetadataExt};
+    let dev = fs::metadata(path).ok()?.dev();
+    let device = Path::new("/sys/dev/block").join(format!("{}:{}", libc::major(dev), libc::minor(dev)));
+    // Partitions don't have a queue of their own, the disk they're on does
+    let flag = fs::read_to_string(device.join("queue/rotational")).or_else(|_| fs::read_to_string(device.join("../queue/rotational"))).ok()?;
+    Some(flag.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_path: &Path) -> Option<bool> {
+    None
+}
+
+/// Resolves `Auto` by looking at the disk under each of `paths`, or their closest existing ancestor. Touching any
+/// spinning disk makes it an hdd run
+pub fn detect<'a>(disk_type: DiskType, paths: impl IntoIterator<Item = &'a Path>) -> DiskType {
+    if disk_type != DiskType::Auto {
+        return disk_type;
+    }
+    let spins = paths.into_iter().any(|path| {
+        let rotational = path.ancestors().find(|ancestor| ancestor.exists()).and_then(is_rotational);
+        debug!("{}: rotational disk: {:?}", path.display(), rotational);
+        rotational == Some(true)
+    });
+    if spins { DiskType::Hdd } else { DiskType::Ssd }
+}
@@ -0,0 +1,47 @@
+/// 4x4 Bayer matrix, each threshold appearing once
+const BAYER: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/// Ordered dithering offset for the pixel at `x`, `y`, between -0.5 and 0.5 of a quantization step
+pub fn ordered_offset(x: u32, y: u32) -> f32 {
+    (BAYER[y as usize % 4][x as usize % 4] as f32 + 0.5) / 16.0 - 0.5
+}
+
+/// Floyd–Steinberg error owed to the row being quantized and the one below it, left to right
+pub struct Diffusion {
+    channels: usize,
+    /// Both rows have a pixel of padding on each side, so the edges need no special casing
+    current: Vec<f32>,
+    next: Vec<f32>,
+}
+
+impl Diffusion {
+    pub fn new(width: u32, channels: usize) -> Self {
+        let len = (width as usize + 2) * channels;
+        Diffusion { channels, current: vec![0.0; len], next: vec![0.0; len] }
+    }
+
+    /// Error diffused onto `channel` of pixel `x` of the current row so far
+    pub fn error(&self, x: u32, channel: usize) -> f32 {
+        self.current[(x as usize + 1) * self.channels + channel]
+    }
+
+    /// Hands `error`, what pixel `x` wanted in `channel` minus what it got, on to the neighbours not quantized yet
+    pub fn spread(&mut self, x: u32, channel: usize, error: f32) {
+        let at = |offset: usize| (x as usize + offset) * self.channels + channel;
+        self.current[at(2)] += error * 7.0 / 16.0;
+        self.next[at(0)] += error * 3.0 / 16.0;
+        self.next[at(1)] += error * 5.0 / 16.0;
+        self.next[at(2)] += error / 16.0;
+    }
+
+    /// Moves on to the row below
+    pub fn next_row(&mut self) {
+        std::mem::swap(&mut self.current, &mut self.next);
+        self.next.fill(0.0);
+    }
+}
@@ -0,0 +1,72 @@
+use std::{error::Error, io, path::PathBuf};
+
+use image::ImageError;
+use thiserror::Error;
+
+/// Everything that can go wrong squashing an image
+#[derive(Debug, Error)]
+pub enum SquashError {
+    /// Reading, writing or renaming a file failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The input, or an encoder's own output, isn't a valid image
+    #[error("couldn't decode: {0}")]
+    Decode(#[source] Box<dyn Error + Send + Sync>),
+    /// An encoder turned the image down
+    #[error("couldn't encode: {0}")]
+    Encode(#[source] Box<dyn Error + Send + Sync>),
+    /// A format, or a feature of one, that isn't supported
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    /// An image with no pixels along one of its axes, which can't be resized
+    #[error("image is {0}x{1}, which has no pixels to resize")]
+    ZeroDimension(u32, u32),
+    /// The output didn't decode back as expected under --verify
+    #[error("output failed verification: {0}")]
+    Verification(String),
+    /// --backup would have replaced an earlier backup without --overwrite-backups
+    #[error("refusing to overwrite existing backup {}", .0.display())]
+    BackupExists(PathBuf),
+    /// The caller gave up on the file through `CompressOptions::abandoned` before anything was written
+    #[error("abandoned before anything was written")]
+    Abandoned,
+}
+
+impl SquashError {
+    /// Sorts an error from decoding with image into the variant it belongs to
+    pub(crate) fn decode(e: ImageError) -> SquashError {
+        match e {
+            // Running out of file partway through is the image's fault, not the filesystem's
+            ImageError::IoError(e) if e.kind() != io::ErrorKind::UnexpectedEof => SquashError::Io(e),
+            ImageError::Unsupported(e) => SquashError::Unsupported(e.to_string()),
+            e => SquashError::Decode(Box::new(e)),
+        }
+    }
+
+    /// Sorts an error from encoding with image into the variant it belongs to
+    pub(crate) fn encode(e: ImageError) -> SquashError {
+        match e {
+            ImageError::IoError(e) => SquashError::Io(e),
+            ImageError::Unsupported(e) => SquashError::Unsupported(e.to_string()),
+            e => SquashError::Encode(Box::new(e)),
+        }
+    }
+}
+
+impl From<png::DecodingError> for SquashError {
+    fn from(e: png::DecodingError) -> SquashError {
+        match e {
+            png::DecodingError::IoError(e) => SquashError::Io(e),
+            e => SquashError::Decode(Box::new(e)),
+        }
+    }
+}
+
+impl From<png::EncodingError> for SquashError {
+    fn from(e: png::EncodingError) -> SquashError {
+        match e {
+            png::EncodingError::IoError(e) => SquashError::Io(e),
+            e => SquashError::Encode(Box::new(e)),
+        }
+    }
+}
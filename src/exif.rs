@@ -0,0 +1,49 @@
+/// EXIF tag holding the orientation
+const ORIENTATION: u16 = 0x112;
+
+/// Byte order of a raw EXIF block, true for big endian. None if it isn't one
+fn is_big_endian(exif: &[u8]) -> Option<bool> {
+    match exif.get(..4)? {
+        [b'I', b'I', 42, 0] => Some(false),
+        [b'M', b'M', 0, 42] => Some(true),
+        _ => None,
+    }
+}
+
+/// Where the orientation value sits in the first IFD of a raw EXIF block, as stored in a png's eXIf chunk
+fn orientation_offset(exif: &[u8]) -> Option<usize> {
+    let big_endian = is_big_endian(exif)?;
+    let u16_at = |at: usize| {
+        let bytes = [*exif.get(at)?, *exif.get(at + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let u32_at = |at: usize| {
+        let bytes = [*exif.get(at)?, *exif.get(at + 1)?, *exif.get(at + 2)?, *exif.get(at + 3)?];
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+    let ifd = u32_at(4)? as usize;
+    // Each 12 byte entry is a tag, a type, a count and the value itself when it fits in 4 bytes
+    (0..u16_at(ifd)? as usize).map(|i| ifd + 2 + i * 12).find(|entry| {
+        // Type 3 is a 16 bit SHORT
+        u16_at(*entry) == Some(ORIENTATION) && u16_at(entry + 2) == Some(3) && u32_at(entry + 4) == Some(1) && u16_at(entry + 8).is_some()
+    }).map(|entry| entry + 8)
+}
+
+/// Reads the orientation value (1-8) from the first IFD of a raw EXIF block
+pub fn orientation(exif: &[u8]) -> Option<u8> {
+    let at = orientation_offset(exif)?;
+    let bytes = [exif[at], exif[at + 1]];
+    let value = if is_big_endian(exif)? { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) };
+    u8::try_from(value).ok()
+}
+
+/// `exif` with its orientation set back to 1, for pixels that have already been turned upright. Everything else in it
+/// is kept as it is
+pub fn reset_orientation(exif: &[u8]) -> Vec<u8> {
+    let mut exif = exif.to_vec();
+    if let (Some(at), Some(big_endian)) = (orientation_offset(&exif), is_big_endian(&exif)) {
+        let upright = if big_endian { 1_u16.to_be_bytes() } else { 1_u16.to_le_bytes() };
+        exif[at..at + 2].copy_from_slice(&upright);
+    }
+    exif
+}
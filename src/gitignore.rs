@@ -0,0 +1,96 @@
+use std::{fs, path::Path};
+
+use crate::glob;
+
+struct Rule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// The rules from a single .gitignore file
+pub struct Gitignore {
+    /// Directory holding the .gitignore, relative to the scan root
+    base: String,
+    /// Path from the .gitignore's directory down to the scan root, for files that live above the root
+    prefix: String,
+    rules: Vec<Rule>,
+}
+
+impl Gitignore {
+    pub fn load(dir: &Path, base: String, prefix: String) -> Option<Gitignore> {
+        let contents = fs::read_to_string(dir.join(".gitignore")).ok()?;
+        Some(Gitignore::parse(&contents, base, prefix))
+    }
+
+    pub fn parse(contents: &str, base: String, prefix: String) -> Gitignore {
+        let rules = contents.lines().filter_map(|line| {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            if line.is_empty() {
+                return None;
+            }
+            // Patterns without an inner slash match at any depth, the rest are anchored to the .gitignore's directory
+            let pattern = if line.contains('/') {
+                line.trim_start_matches('/').to_string()
+            } else {
+                format!("**/{}", line)
+            };
+            Some(Rule { pattern, negated, dir_only })
+        }).collect();
+        Gitignore { base, prefix, rules }
+    }
+
+    /// Returns `Some(true)` if the last matching rule ignores the path, `Some(false)` if it re-includes it
+    pub fn matched(&self, relative: &str, is_dir: bool) -> Option<bool> {
+        let local = if !self.prefix.is_empty() {
+            format!("{}/{}", self.prefix, relative)
+        } else if self.base.is_empty() {
+            relative.to_string()
+        } else {
+            relative.strip_prefix(&format!("{}/", self.base))?.to_string()
+        };
+        self.rules.iter().rev()
+            .find(|rule| (is_dir || !rule.dir_only) && glob::matches(&rule.pattern, &local))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Deeper .gitignore files take precedence over shallower ones, as in git
+pub fn is_ignored(gitignores: &[Gitignore], relative: &str, is_dir: bool) -> bool {
+    gitignores.iter().rev().find_map(|gitignore| gitignore.matched(relative, is_dir)).unwrap_or(false)
+}
+
+/// Loads the .gitignore files between the enclosing repository's root and `root`, outermost first.
+/// `root`'s own .gitignore is not included
+pub fn ancestors(root: &Path) -> Vec<Gitignore> {
+    let Ok(root) = root.canonicalize() else {
+        return vec![];
+    };
+    let mut found = vec![];
+    let mut prefix: Vec<String> = vec![];
+    let mut dir = root.as_path();
+    while !dir.join(".git").exists() {
+        let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+            // Not inside a repository, so nothing above the root applies
+            return vec![];
+        };
+        prefix.insert(0, name.to_string_lossy().to_string());
+        dir = parent;
+        if let Some(gitignore) = Gitignore::load(dir, String::new(), prefix.join("/")) {
+            found.push(gitignore);
+        }
+    }
+    found.reverse();
+    found
+}
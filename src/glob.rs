@@ -0,0 +1,35 @@
+/// Matches `path` against a glob `pattern`. `*` and `?` stay within a single path component while `**` spans any
+/// number of components. Paths are expected to use `/` separators
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    match_from(&pattern, &path)
+}
+
+fn match_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // "**/" is also allowed to match zero directories
+            if rest.first() == Some(&'/') && match_from(&rest[1..], path) {
+                return true;
+            }
+            (0..=path.len()).any(|i| match_from(rest, &path[i..]))
+        },
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=path.len() {
+                if match_from(rest, &path[i..]) {
+                    return true;
+                }
+                if path.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        },
+        Some('?') => path.first().is_some_and(|c| *c != '/') && match_from(&pattern[1..], &path[1..]),
+        Some(c) => path.first() == Some(c) && match_from(&pattern[1..], &path[1..]),
+    }
+}
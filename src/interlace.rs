@@ -0,0 +1,122 @@
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+
+use crate::{chunks::{self, Chunk}, Compression, RowFilter, SquashError};
+
+/// First column, first row, column step and row step of each Adam7 pass
+const PASSES: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+/// Copies the `bits` wide pixel at bit `from` of `src` to bit `to` of `dst`. Pixels are whole bytes, or pack evenly
+/// into them with the leftmost one in the high bits
+fn copy_pixel(src: &[u8], from: usize, dst: &mut [u8], to: usize, bits: usize) {
+    if bits >= 8 {
+        dst[to / 8..(to + bits) / 8].copy_from_slice(&src[from / 8..(from + bits) / 8]);
+    } else {
+        let mask = (1u8 << bits) - 1;
+        let value = (src[from / 8] >> (8 - bits - from % 8)) & mask;
+        dst[to / 8] |= value << (8 - bits - to % 8);
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// `row` run through png filter `kind`, led by the filter type byte. `bpp` is the whole bytes per pixel, at least 1
+fn filter(kind: u8, row: &[u8], previous: &[u8], bpp: usize) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(row.len() + 1);
+    filtered.push(kind);
+    filtered.extend(row.iter().enumerate().map(|(i, &x)| {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let c = if i >= bpp { previous[i - bpp] } else { 0 };
+        let b = previous[i];
+        x.wrapping_sub(match kind {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        })
+    }));
+    filtered
+}
+
+/// Filters `row` the way `row_filter` asks. Adaptive keeps whichever filter has the smallest sum of absolute
+/// differences, the usual guess at what deflates best
+fn filter_row(row: &[u8], previous: &[u8], bpp: usize, row_filter: RowFilter) -> Vec<u8> {
+    let kinds = match row_filter {
+        RowFilter::NoFilter => 0..=0,
+        RowFilter::Sub => 1..=1,
+        RowFilter::Up => 2..=2,
+        RowFilter::Avg => 3..=3,
+        RowFilter::Paeth => 4..=4,
+        RowFilter::Adaptive => 0..=4,
+    };
+    kinds.map(|kind| filter(kind, row, previous, bpp))
+        .min_by_key(|filtered| filtered[1..].iter().map(|&byte| (byte as i8).unsigned_abs() as u64).sum::<u64>())
+        .unwrap()
+}
+
+/// Re-encodes `png` with Adam7 interlacing, so it can be shown at low detail before it has fully loaded. Every chunk
+/// besides IHDR and the image data is carried over untouched
+pub fn adam7(png: &[u8], compression: Compression, row_filter: RowFilter) -> Result<Vec<u8>, SquashError> {
+    let mut decoder = png::Decoder::new(png);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let bits = reader.info().bits_per_pixel();
+    let mut raw = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut raw)?;
+    let (width, height) = (frame.width as usize, frame.height as usize);
+
+    let mut data = vec![];
+    for (x0, y0, dx, dy) in PASSES {
+        // Passes without any pixels in them are left out entirely
+        if x0 >= width {
+            continue;
+        }
+        let pass_width = (width - x0).div_ceil(dx);
+        let mut previous = vec![0; (pass_width * bits).div_ceil(8)];
+        for y in (y0..height).step_by(dy) {
+            let line = &raw[y * frame.line_size..][..frame.line_size];
+            let mut row = vec![0; previous.len()];
+            for (i, x) in (x0..width).step_by(dx).enumerate() {
+                copy_pixel(line, x * bits, &mut row, i * bits, bits);
+            }
+            data.extend(filter_row(&row, &previous, bits.div_ceil(8), row_filter));
+            previous = row;
+        }
+    }
+    let level = match compression {
+        Compression::Fast => flate2::Compression::fast(),
+        Compression::Default => flate2::Compression::default(),
+        Compression::Best => flate2::Compression::best(),
+    };
+    let mut deflater = ZlibEncoder::new(vec![], level);
+    deflater.write_all(&data)?;
+    let mut idat = Some(Chunk { kind: *b"IDAT", data: deflater.finish()? });
+
+    let mut rewritten = vec![];
+    for mut chunk in chunks::parse(png) {
+        match &chunk.kind {
+            // The interlace method is the last byte of IHDR
+            b"IHDR" => chunk.data[12] = 1,
+            // The new image data takes the place of the first IDAT, and the rest are dropped
+            b"IDAT" => {
+                rewritten.extend(idat.take());
+                continue;
+            },
+            _ => {},
+        }
+        rewritten.push(chunk);
+    }
+    Ok(chunks::serialize(&rewritten))
+}
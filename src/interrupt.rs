@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code for runs stopped by Ctrl-C, following the shell's 128 + SIGINT convention
+pub const EXIT_CODE: u8 = 130;
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    // A second Ctrl-C means the user doesn't want to wait for in-flight pngs
+    if INTERRUPTED.swap(true, Ordering::Relaxed) {
+        unsafe { libc::_exit(EXIT_CODE as libc::c_int) };
+    }
+    // Some platforms reset the handler once it fires
+    install();
+}
+
+/// Routes Ctrl-C to `interrupted` instead of killing the process
+pub fn install() {
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+/// True once Ctrl-C has been pressed
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,1378 @@
+use std::{collections::HashSet, env, error::Error, fs::{self, FileTimes}, io::{self, BufReader, Cursor, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::Duration};
+
+use log::{debug, info, warn};
+use image::{codecs::{avif::AvifEncoder, gif::GifDecoder, jpeg::JpegEncoder, png::{CompressionType, FilterType as PngFilterType, PngEncoder}, webp::WebPEncoder}, imageops::{self, FilterType}, metadata::Orientation, AnimationDecoder, ColorType, DynamicImage, GenericImage, GenericImageView, ImageDecoder, ImageReader, Rgb, RgbImage, Rgba};
+use tempfile::NamedTempFile;
+
+pub use discover::{find_png_paths, has_png_signature, InputFormat, ScanOptions};
+pub use error::SquashError;
+
+mod apng;
+mod chunks;
+mod dither;
+mod discover;
+mod error;
+mod exif;
+mod gitignore;
+mod interlace;
+pub mod glob;
+pub mod json;
+mod posterize;
+mod quantize;
+mod resize;
+
+/// Resampling filter used when scaling images down. Named after `image`'s `FilterType`
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug)]
+pub enum Filter {
+    #[value(alias = "nearest-neighbor")]
+    Nearest,
+    #[value(alias = "linear-triangle")]
+    Triangle,
+    CatmullRom,
+    #[default]
+    Gaussian,
+    #[value(alias = "lanczos")]
+    Lanczos3,
+    /// Nearest for images that look like pixel art, Lanczos3 for everything else
+    Auto,
+}
+
+/// Small images with few colors are most likely pixel art, which only nearest neighbour scales without smearing.
+/// Antialiased edges and photographic detail both take lots of in-between colors, so the palette check also stands in for
+/// looking for hard edges
+fn looks_like_pixel_art(image: &DynamicImage, opts: &CompressOptions) -> bool {
+    if image.width().max(image.height()) > opts.pixel_art_max_dimension {
+        return false;
+    }
+    let mut colors = HashSet::new();
+    image.pixels().all(|(_, _, pixel)| {
+        colors.insert(pixel.0);
+        colors.len() <= opts.pixel_art_max_colors
+    })
+}
+
+/// The filter `image` is actually scaled with, never `Auto`
+fn resolve_filter(image: &DynamicImage, opts: &CompressOptions) -> Filter {
+    match opts.filter {
+        _ if opts.auto_filter && looks_like_pixel_art(image, opts) => Filter::Nearest,
+        Filter::Auto if looks_like_pixel_art(image, opts) => Filter::Nearest,
+        Filter::Auto => Filter::Lanczos3,
+        filter => filter,
+    }
+}
+
+fn convert_filter(filter: Filter) -> FilterType {
+    match filter {
+        Filter::Nearest => FilterType::Nearest,
+        Filter::Triangle => FilterType::Triangle,
+        Filter::CatmullRom => FilterType::CatmullRom,
+        Filter::Gaussian => FilterType::Gaussian,
+        Filter::Lanczos3 | Filter::Auto => FilterType::Lanczos3,
+    }
+}
+
+/// Encoding used for the compressed output
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Png,
+    /// Lossless WebP
+    Webp,
+    Avif,
+    /// Lossy, at `quality`. Best for photos
+    #[value(alias = "jpg")]
+    Jpeg,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+            Format::Jpeg => "jpg",
+        }
+    }
+
+    pub fn supports_alpha(self) -> bool {
+        self != Format::Jpeg
+    }
+}
+
+/// Precision kept for images with 16 bits per channel
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    #[value(name = "8")]
+    Eight,
+    /// Leave 16 bit images as they are
+    #[default]
+    #[value(name = "16")]
+    Sixteen,
+    /// 16 bit only for images with values that don't fit in 8 bits
+    Auto,
+}
+
+/// What to do with gifs that have more than one frame
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum AnimatedGifs {
+    /// Leave them alone, with a warning
+    #[default]
+    Skip,
+    /// Squash the first frame into a still image
+    FirstFrame,
+}
+
+/// Deflate effort used by the png encoder
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug)]
+pub enum Compression {
+    Fast,
+    Default,
+    #[default]
+    Best,
+}
+
+fn convert_compression(compression: Compression) -> CompressionType {
+    match compression {
+        Compression::Fast => CompressionType::Fast,
+        Compression::Default => CompressionType::Default,
+        Compression::Best => CompressionType::Best,
+    }
+}
+
+/// Which power of two each side of an image is snapped to
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PowerOfTwo {
+    /// The smallest power of two at least as big as the side
+    Up,
+    /// The largest power of two no bigger than the side
+    Down,
+    /// Whichever is closer, rounding down on ties
+    Nearest,
+}
+
+impl PowerOfTwo {
+    /// Snaps `size` to a power of two, never going over `max`
+    fn snap(self, size: u32, max: Option<u32>) -> u32 {
+        let below = |size: u32| 1 << (31 - size.max(1).leading_zeros());
+        let (down, up) = (below(size), size.max(1).checked_next_power_of_two().unwrap_or(below(size)));
+        let snapped = match self {
+            PowerOfTwo::Up => up,
+            PowerOfTwo::Down => down,
+            PowerOfTwo::Nearest if up - size < size - down => up,
+            PowerOfTwo::Nearest => down,
+        };
+        match max {
+            Some(max) if snapped > max => below(max),
+            _ => snapped,
+        }
+    }
+}
+
+/// How lossy reductions spread their rounding error, so gradients don't band
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dither {
+    /// Round every pixel on its own. The smallest output, but gradients band
+    None,
+    /// Carry each pixel's rounding error over to its neighbours. The smoothest result
+    FloydSteinberg,
+    /// Nudge pixels by a fixed 4x4 pattern. Less smooth, but the regular pattern deflates better
+    Ordered,
+}
+
+/// How the pixels of a png are laid out
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum Interlace {
+    /// Row by row. The smallest option
+    #[default]
+    None,
+    /// Seven passes of increasing detail, so browsers can show a rough version while the rest loads
+    Adam7,
+}
+
+/// Per-row filter the png encoder applies before deflating
+#[derive(clap::ValueEnum, Copy, Clone, Default, Debug)]
+pub enum RowFilter {
+    NoFilter,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// Picks the best filter for each row
+    #[default]
+    Adaptive,
+}
+
+fn convert_row_filter(row_filter: RowFilter) -> PngFilterType {
+    match row_filter {
+        RowFilter::NoFilter => PngFilterType::NoFilter,
+        RowFilter::Sub => PngFilterType::Sub,
+        RowFilter::Up => PngFilterType::Up,
+        RowFilter::Avg => PngFilterType::Avg,
+        RowFilter::Paeth => PngFilterType::Paeth,
+        RowFilter::Adaptive => PngFilterType::Adaptive,
+    }
+}
+
+/// Settings shared by every file in a run
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    /// Maximum width in pixels. Larger images will be scaled down
+    pub max_width: Option<u32>,
+    /// Maximum height in pixels. Larger images will be scaled down
+    pub max_height: Option<u32>,
+    /// Maximum width times height. Larger images will be scaled down evenly on both axes
+    pub max_pixels: Option<u64>,
+    pub filter: Filter,
+    /// Use nearest neighbour for images that look like pixel art, and `filter` for the rest
+    pub auto_filter: bool,
+    /// Images with more colors than this aren't treated as pixel art
+    pub pixel_art_max_colors: usize,
+    /// Images wider or taller than this aren't treated as pixel art
+    pub pixel_art_max_dimension: u32,
+    /// Encode and measure but never touch the output file
+    pub dry_run: bool,
+    /// Decode every output in full before it replaces anything, failing the file if that doesn't work
+    pub verify: bool,
+    /// How many more times to try replacing the output after a transient filesystem error
+    pub retries: u32,
+    /// Copy an existing output to `<output><suffix>` before replacing it
+    pub backup: Option<String>,
+    /// Replace backups left over from a previous run instead of failing
+    pub overwrite_backups: bool,
+    /// Replace the output with the re-encode even when it's bigger
+    pub force: bool,
+    /// Only replace the output when the re-encode is at least this many percent smaller
+    pub overwrite_threshold: u8,
+    /// Files smaller than this many bytes are left untouched
+    pub min_size: u64,
+    /// Images whose longer side is shorter than this many pixels are left untouched
+    pub min_dimension: Option<u32>,
+    /// Images whose longer side is longer than this many pixels are left untouched
+    pub max_dimension: Option<u32>,
+    /// Leave pngs that are already within the size limits untouched instead of re-encoding them, unless `force` is set
+    pub skip_within_limits: bool,
+    /// An image only keeps its alpha channel if some pixel is less opaque than this.
+    /// 255 strips alpha only from fully opaque images
+    pub alpha_threshold: u8,
+    /// Never strip the alpha channel, even from opaque images
+    pub keep_alpha: bool,
+    pub bit_depth: BitDepth,
+    /// Store color images whose pixels are all gray as grayscale
+    pub auto_grayscale: bool,
+    /// Store images with the fewest channels that hold them exactly: gray, gray with alpha, rgb or rgba
+    pub channel_optimize: bool,
+    /// Rotate and flip pngs the way their EXIF orientation says to. The eXIf chunk is kept, with its orientation reset
+    /// to 1 when the pixels were turned so viewers don't apply it a second time, and left as it is otherwise
+    pub auto_orient: bool,
+    /// Crop away fully transparent rows and columns at the edges before resizing
+    pub trim: bool,
+    pub format: Format,
+    /// JPEG quality, 1-100
+    pub quality: u8,
+    /// Color transparent images are composited over when `format` can't hold alpha
+    pub background: [u8; 3],
+    pub animated_gifs: AnimatedGifs,
+    /// Squash every frame of animated pngs instead of skipping them
+    pub apng: bool,
+    pub compression: Compression,
+    pub row_filter: RowFilter,
+    /// Interlacing of png output
+    pub interlace: Interlace,
+    /// Also encode png output with every row filter at the best and default compression, keeping the smallest
+    pub try_all_filters: bool,
+    /// Lossy: reduce png output to a palette of at most this many colors (2-256)
+    pub quantize: Option<u16>,
+    /// Lossy: snap each color channel to this many evenly spaced levels (2-256) before encoding
+    pub posterize: Option<u16>,
+    /// Dithering for the lossy reductions: `quantize`, `posterize` and dropping 16 bit images to 8 bits. None leaves
+    /// it to each one, which means Floyd–Steinberg for `quantize` and no dithering for the others
+    pub dither: Option<Dither>,
+    /// Scale every image to this percentage of its size instead of applying `max_width`/`max_height`
+    pub percent: Option<u8>,
+    /// Snap each side to a power of two after the size limits, which `max_width` and `max_height` still cap
+    pub power_of_two: Option<PowerOfTwo>,
+    /// Extra factor applied after the size limits, for writing a smaller variant of each image. Variants at any other
+    /// scale than 1 are compared against what's already at their output path, never against the input
+    pub scale: f32,
+    /// Shrink images further, as little as possible, until they encode to at most this many bytes
+    pub target_size: Option<u64>,
+    /// Give the output the input's modified and accessed times
+    pub preserve_mtime: bool,
+    /// Split the resize of images over `BIG_IMAGE_PIXELS` across this many threads
+    pub big_image_threads: Option<usize>,
+    /// Sigma of an unsharp mask applied to resized images, to counteract the softening of downscaling
+    pub sharpen: Option<f32>,
+    /// Pad resized images on the right and bottom up to the next multiple of this many pixels on each axis
+    pub pad_to_multiple: Option<u32>,
+    /// Drop text chunks (Title, Author, Copyright, ...) and EXIF instead of copying them from the source png
+    pub strip_metadata: bool,
+    /// Drop the source's ICC profile. Color-managed viewers then treat the output as sRGB, which can shift the
+    /// colors of images made for wider gamuts
+    pub strip_icc: bool,
+    /// Leave nothing but the critical chunks (and tRNS) in png output
+    pub strip_all: bool,
+    /// Mark png output as this many dots per inch, instead of scaling the input's own pHYs to the new size
+    pub dpi: Option<u32>,
+    /// Convert pixels from the gamma the source declares in its gAMA chunk, or sRGB's without one, to this display
+    /// gamma, and mark png output with a matching gAMA
+    pub normalize_gamma: Option<f64>,
+    /// Set to give up on a file partway, e.g. once it's taken too long. Nothing is backed up, replaced or copied after
+    /// it's set, and the file fails with `SquashError::Abandoned`
+    pub abandoned: Option<Arc<AtomicBool>>,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            filter: Filter::default(),
+            auto_filter: false,
+            pixel_art_max_colors: 256,
+            pixel_art_max_dimension: 512,
+            dry_run: false,
+            verify: false,
+            retries: 3,
+            backup: None,
+            overwrite_backups: false,
+            force: false,
+            overwrite_threshold: 0,
+            min_size: 0,
+            min_dimension: None,
+            max_dimension: None,
+            skip_within_limits: false,
+            alpha_threshold: 254,
+            keep_alpha: false,
+            bit_depth: BitDepth::default(),
+            auto_grayscale: false,
+            channel_optimize: false,
+            auto_orient: true,
+            trim: false,
+            format: Format::default(),
+            quality: 85,
+            background: [255, 255, 255],
+            animated_gifs: AnimatedGifs::default(),
+            apng: false,
+            compression: Compression::default(),
+            row_filter: RowFilter::default(),
+            interlace: Interlace::default(),
+            try_all_filters: false,
+            quantize: None,
+            posterize: None,
+            dither: None,
+            percent: None,
+            power_of_two: None,
+            scale: 1.0,
+            target_size: None,
+            preserve_mtime: false,
+            big_image_threads: None,
+            sharpen: None,
+            pad_to_multiple: None,
+            strip_metadata: false,
+            strip_icc: false,
+            strip_all: false,
+            dpi: None,
+            normalize_gamma: None,
+            abandoned: None,
+        }
+    }
+}
+
+/// Outcome of compressing a single file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompressStats {
+    pub original_size: u64,
+    pub new_size: u64,
+    /// True when the file was left as is, either because no encoding beat the original or it was filtered out
+    pub skipped: bool,
+    /// True when the file was skipped before being decoded because it didn't meet the configured limits
+    pub filtered: bool,
+    /// True when `target_size` was set but the file still ended up bigger
+    pub over_target: bool,
+    /// Width and height of the input. Not known for files filtered out by size
+    pub original_dimensions: Option<(u32, u32)>,
+    /// Width and height of the output
+    pub new_dimensions: Option<(u32, u32)>,
+    /// Filter the output was scaled with, if it was scaled at all
+    pub filter: Option<Filter>,
+}
+
+/// One encode of the input, competing to be the output
+struct Candidate {
+    encoded: NamedTempFile,
+    size: u64,
+    color: ColorType,
+    dimensions: (u32, u32),
+    filter: Filter,
+}
+
+impl CompressStats {
+    fn filtered(original_size: u64) -> CompressStats {
+        CompressStats { original_size, new_size: original_size, skipped: true, filtered: true, ..CompressStats::default() }
+    }
+}
+
+/// True if `image` is 16 bit and some value carries more than 8 bits of precision. An 8 bit value `v` widens to
+/// `v * 257`, whose high and low bytes are equal, whatever the byte order
+fn needs_16_bits(image: &DynamicImage) -> bool {
+    matches!(image.color(), ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16)
+        && image.as_bytes().chunks_exact(2).any(|pair| pair[0] != pair[1])
+}
+
+/// Encoding gamma assumed for images that don't declare one, which is roughly sRGB's
+const SRGB_GAMMA: f64 = 1.0 / 2.2;
+
+/// The power to raise samples to for --normalize-gamma, given the source's chunks. None when it's off, or the source
+/// already has the target gamma
+pub(crate) fn gamma_exponent(source_chunks: &[chunks::Chunk], opts: &CompressOptions) -> Option<f64> {
+    let target = opts.normalize_gamma?;
+    let exponent = 1.0 / (target * chunks::gamma(source_chunks).unwrap_or(SRGB_GAMMA));
+    Some(exponent).filter(|exponent| (exponent - 1.0).abs() > 0.001)
+}
+
+/// Raises every color sample of `samples`, normalized to 0..1, to `exponent` through `lut`. Alpha, the last of each
+/// pixel's `channels` when `alpha` is set, is left as is
+fn apply_gamma<T: Copy + Into<usize>>(samples: &mut [T], channels: usize, alpha: bool, lut: &[T]) {
+    for pixel in samples.chunks_exact_mut(channels) {
+        let color = if alpha { channels - 1 } else { channels };
+        for sample in &mut pixel[..color] {
+            *sample = lut[(*sample).into()];
+        }
+    }
+}
+
+/// Re-encodes `image`'s color samples with gamma `exponent`, i.e. raises each of them, as a fraction of full scale,
+/// to that power
+pub(crate) fn correct_gamma(image: &mut DynamicImage, exponent: f64) {
+    let (channels, alpha) = (image.color().channel_count() as usize, image.color().has_alpha());
+    let lut8: Vec<u8> = (0..=u8::MAX).map(|v| ((v as f64 / 255.0).powf(exponent) * 255.0).round() as u8).collect();
+    let lut16 = || -> Vec<u16> { (0..=u16::MAX).map(|v| ((v as f64 / 65535.0).powf(exponent) * 65535.0).round() as u16).collect() };
+    match image {
+        DynamicImage::ImageLuma8(buffer) => apply_gamma(buffer, channels, alpha, &lut8),
+        DynamicImage::ImageLumaA8(buffer) => apply_gamma(buffer, channels, alpha, &lut8),
+        DynamicImage::ImageRgb8(buffer) => apply_gamma(buffer, channels, alpha, &lut8),
+        DynamicImage::ImageRgba8(buffer) => apply_gamma(buffer, channels, alpha, &lut8),
+        DynamicImage::ImageLuma16(buffer) => apply_gamma(buffer, channels, alpha, &lut16()),
+        DynamicImage::ImageLumaA16(buffer) => apply_gamma(buffer, channels, alpha, &lut16()),
+        DynamicImage::ImageRgb16(buffer) => apply_gamma(buffer, channels, alpha, &lut16()),
+        DynamicImage::ImageRgba16(buffer) => apply_gamma(buffer, channels, alpha, &lut16()),
+        DynamicImage::ImageRgb32F(buffer) => buffer.pixels_mut().flat_map(|pixel| pixel.0.iter_mut()).for_each(|sample| *sample = sample.max(0.0).powf(exponent as f32)),
+        DynamicImage::ImageRgba32F(buffer) => buffer.pixels_mut().flat_map(|pixel| pixel.0[..3].iter_mut()).for_each(|sample| *sample = sample.max(0.0).powf(exponent as f32)),
+        _ => {},
+    }
+}
+
+fn reduce_bit_depth(mut image: DynamicImage, bit_depth: BitDepth, dither: Option<Dither>) -> DynamicImage {
+    match bit_depth {
+        BitDepth::Eight => {
+            // 16 bit samples that are multiples of 257 convert to 8 bits exactly, so dithering to those is the whole job
+            if let Some(dither) = dither && needs_16_bits(&image) {
+                posterize::posterize(&mut image, 256, dither);
+            }
+            to_8bit(image)
+        },
+        BitDepth::Sixteen => image,
+        BitDepth::Auto if needs_16_bits(&image) => image,
+        BitDepth::Auto => to_8bit(image),
+    }
+}
+
+/// True if `image` is stored as color but every pixel has equal red, green and blue
+fn is_grayscale(image: &DynamicImage) -> bool {
+    let (pixel_bytes, sample_bytes) = match image.color() {
+        ColorType::Rgb8 => (3, 1),
+        ColorType::Rgba8 => (4, 1),
+        ColorType::Rgb16 => (6, 2),
+        ColorType::Rgba16 => (8, 2),
+        _ => return false,
+    };
+    image.as_bytes().chunks_exact(pixel_bytes).all(|pixel| {
+        let (red, rest) = pixel.split_at(sample_bytes);
+        red == &rest[..sample_bytes] && red == &rest[sample_bytes..2 * sample_bytes]
+    })
+}
+
+/// Drops the color channels of an `is_grayscale` image, keeping its alpha and bit depth. Luma is a weighted sum of
+/// the channels with weights adding up to 1, so equal channels come through unchanged
+fn to_grayscale(image: DynamicImage) -> DynamicImage {
+    match image.color() {
+        ColorType::Rgb8 => image.to_luma8().into(),
+        ColorType::Rgba8 => image.to_luma_alpha8().into(),
+        ColorType::Rgb16 => image.to_luma16().into(),
+        ColorType::Rgba16 => image.to_luma_alpha16().into(),
+        _ => image,
+    }
+}
+
+/// Bytes the decoded image takes up in memory, worked out from its header
+pub fn decoded_size(file_path: &Path) -> Result<u64, SquashError> {
+    Ok(ImageReader::open(file_path)?.with_guessed_format()?.into_decoder().map_err(SquashError::decode)?.total_bytes())
+}
+
+/// Orientation from a png's eXIf chunk, if it has one that calls for any rotating or flipping. image doesn't read
+/// EXIF from pngs itself
+fn exif_orientation(chunks: &[chunks::Chunk]) -> Option<Orientation> {
+    let exif = chunks.iter().find(|chunk| chunk.kind == chunks::EXIF);
+    let orientation = exif.and_then(|chunk| exif::orientation(&chunk.data)).and_then(Orientation::from_exif);
+    orientation.filter(|orientation| *orientation != Orientation::NoTransforms)
+}
+
+/// True if every pixel's alpha is at its maximum, so dropping the channel loses nothing. False for images without one
+fn is_fully_opaque(image: &DynamicImage) -> bool {
+    let (pixel_bytes, sample_bytes) = match image.color() {
+        ColorType::La8 => (2, 1),
+        ColorType::Rgba8 => (4, 1),
+        ColorType::La16 => (4, 2),
+        ColorType::Rgba16 => (8, 2),
+        _ => return false,
+    };
+    image.as_bytes().chunks_exact(pixel_bytes).all(|pixel| pixel[pixel_bytes - sample_bytes..].iter().all(|byte| *byte == 0xFF))
+}
+
+/// Drops the alpha channel, keeping the color channels and bit depth
+fn drop_alpha(image: DynamicImage) -> DynamicImage {
+    match image.color() {
+        ColorType::La8 => image.to_luma8().into(),
+        ColorType::La16 => image.to_luma16().into(),
+        ColorType::Rgba16 => image.to_rgb16().into(),
+        color if color.has_alpha() => image.to_rgb8().into(),
+        _ => image,
+    }
+}
+
+/// Converts `image` to the fewest channels that hold it exactly, only ever dropping alpha when `keep_alpha` is unset
+fn minimal_channels(image: DynamicImage, keep_alpha: bool) -> DynamicImage {
+    let image = if is_grayscale(&image) { to_grayscale(image) } else { image };
+    if !keep_alpha && is_fully_opaque(&image) { drop_alpha(image) } else { image }
+}
+
+/// The smallest region, as x, y, width and height, holding every pixel that isn't fully transparent. None for images
+/// without alpha, and for ones with nothing to crop or nothing to keep
+fn visible_bounds(image: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+    let (pixel_bytes, sample_bytes) = match image.color() {
+        ColorType::La8 => (2, 1),
+        ColorType::Rgba8 => (4, 1),
+        ColorType::La16 => (4, 2),
+        ColorType::Rgba16 => (8, 2),
+        ColorType::Rgba32F => (16, 4),
+        _ => return None,
+    };
+    let width = image.width() as usize;
+    let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+    for (i, pixel) in image.as_bytes().chunks_exact(pixel_bytes).enumerate() {
+        if pixel[pixel_bytes - sample_bytes..].iter().any(|byte| *byte != 0) {
+            let (x, y) = (i % width, i / width);
+            (left, top, right, bottom) = (left.min(x), top.min(y), right.max(x), bottom.max(y));
+        }
+    }
+    if left == usize::MAX {
+        return None;
+    }
+    let bounds = (left as u32, top as u32, (right - left + 1) as u32, (bottom - top + 1) as u32);
+    (bounds != (0, 0, image.width(), image.height())).then_some(bounds)
+}
+
+/// True if no pixel is less opaque than `alpha_threshold`, so the alpha channel could go
+fn is_opaque(image: &DynamicImage, alpha_threshold: u8) -> bool {
+    image.pixels().all(|p| p.2.0[3] >= alpha_threshold)
+}
+
+/// Blends `image` over an opaque `background` color
+fn flatten(image: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let mut flat = RgbImage::new(image.width(), image.height());
+    for (x, y, pixel) in image.pixels() {
+        let [r, g, b, alpha] = pixel.0;
+        let blend = |color: u8, background: u8| ((color as u32 * alpha as u32 + background as u32 * (255 - alpha as u32) + 127) / 255) as u8;
+        flat.put_pixel(x, y, Rgb([blend(r, background[0]), blend(g, background[1]), blend(b, background[2])]));
+    }
+    flat.into()
+}
+
+/// What a quick look at an image turns up, without compressing it
+pub struct ImageInfo {
+    pub dimensions: (u32, u32),
+    pub color: ColorType,
+    /// Whether every pixel is at least `alpha_threshold` opaque. None for images without an alpha channel
+    pub opaque: Option<bool>,
+}
+
+/// Reads the header of `file_path`, decoding the pixels only when it has an alpha channel to check
+pub fn inspect(file_path: &Path, alpha_threshold: u8) -> Result<ImageInfo, SquashError> {
+    let decoder = ImageReader::open(file_path)?.with_guessed_format()?.into_decoder().map_err(SquashError::decode)?;
+    let (dimensions, color) = (decoder.dimensions(), decoder.color_type());
+    let opaque = if color.has_alpha() {
+        Some(is_opaque(&DynamicImage::from_decoder(decoder).map_err(SquashError::decode)?, alpha_threshold))
+    } else {
+        None
+    };
+    Ok(ImageInfo { dimensions, color, opaque })
+}
+
+pub fn load_and_preprocess(file_path: &Path, opts: &CompressOptions) -> Result<Vec<DynamicImage>, SquashError> {
+    let loaded_image = ImageReader::open(file_path)?.with_guessed_format()?.decode().map_err(SquashError::decode)?;
+    let source_chunks = if opts.auto_orient || opts.normalize_gamma.is_some() { chunks::read(file_path)? } else { vec![] };
+    Ok(preprocess(loaded_image, &source_chunks, &file_path.display().to_string(), opts))
+}
+
+/// The candidates worth encoding for `loaded_image`, after the gamma, bit depth, orientation and channel changes
+/// `opts` call for. `source_chunks` are those of the png it was decoded from, if any. `name` is only for logging
+fn preprocess(mut loaded_image: DynamicImage, source_chunks: &[chunks::Chunk], name: &str, opts: &CompressOptions) -> Vec<DynamicImage> {
+    debug!("{}: decoded {}x{} {:?}", name, loaded_image.width(), loaded_image.height(), loaded_image.color());
+    // Before the bit depth goes, so 16 bit images are corrected at full precision
+    if let Some(exponent) = gamma_exponent(source_chunks, opts) {
+        debug!("{}: converting to gamma {} by raising samples to {:.3}", name, opts.normalize_gamma.unwrap_or_default(), exponent);
+        correct_gamma(&mut loaded_image, exponent);
+    }
+    let mut loaded_image = reduce_bit_depth(loaded_image, opts.bit_depth, opts.dither);
+    let orientation = if opts.auto_orient { exif_orientation(source_chunks) } else { None };
+    if let Some(orientation) = orientation {
+        debug!("{}: EXIF orientation is {:?}, applying it", name, orientation);
+        loaded_image.apply_orientation(orientation);
+    }
+    if opts.trim && let Some((x, y, width, height)) = visible_bounds(&loaded_image) {
+        // The offset is what it takes to line a trimmed sprite back up with the others
+        info!("{}: trimmed transparent borders from {}x{}, keeping {}x{} at offset {},{}", name, loaded_image.width(), loaded_image.height(), width, height, x, y);
+        loaded_image = loaded_image.crop_imm(x, y, width, height);
+    }
+    let loaded_image = if opts.channel_optimize {
+        let color = loaded_image.color();
+        let optimized = minimal_channels(loaded_image, opts.keep_alpha && opts.format.supports_alpha());
+        if optimized.color() != color {
+            debug!("{}: {:?} holds every pixel exactly, converting from {:?}", name, optimized.color(), color);
+        }
+        optimized
+    } else if opts.auto_grayscale && is_grayscale(&loaded_image) {
+        debug!("{}: every pixel is gray, converting to grayscale", name);
+        to_grayscale(loaded_image)
+    } else {
+        loaded_image
+    };
+    if !loaded_image.color().has_alpha() || (opts.keep_alpha && opts.format.supports_alpha()) {
+        return vec![loaded_image];
+    }
+    
+    if !is_opaque(&loaded_image, opts.alpha_threshold) {
+        if opts.format.supports_alpha() {
+            return vec![loaded_image];
+        }
+        debug!("{}: has transparency, which {:?} can't hold, flattening it onto the background", name, opts.format);
+        vec![flatten(&loaded_image, opts.background)]
+    } else {
+        debug!("{}: alpha channel is effectively opaque, also trying without it", name);
+        let stripped_image = drop_alpha(loaded_image.clone());
+        if opts.format.supports_alpha() {
+            vec![loaded_image, stripped_image]
+        } else {
+            vec![stripped_image]
+        }
+    }
+}
+
+fn is_animated_gif(path: &Path) -> Result<bool, SquashError> {
+    let decoder = GifDecoder::new(BufReader::new(fs::File::open(path)?)).map_err(SquashError::decode)?;
+    Ok(decoder.into_frames().take(2).count() > 1)
+}
+
+/// WebP and AVIF only take 8 bit images
+fn to_8bit(image: DynamicImage) -> DynamicImage {
+    match image.color() {
+        ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8 => image,
+        ColorType::L16 => image.to_luma8().into(),
+        ColorType::La16 => image.to_luma_alpha8().into(),
+        color if color.has_alpha() => image.to_rgba8().into(),
+        _ => image.to_rgb8().into(),
+    }
+}
+
+fn encode_png(image: &DynamicImage, compression: Compression, row_filter: RowFilter) -> Result<Vec<u8>, SquashError> {
+    let mut encoded = vec![];
+    image.write_with_encoder(PngEncoder::new_with_quality(&mut encoded, convert_compression(compression), convert_row_filter(row_filter))).map_err(SquashError::encode)?;
+    Ok(encoded)
+}
+
+/// Encoder settings `try_all_filters` retries the encode with: every row filter, at the best and the default
+/// compression. The default sometimes wins, since a better match for one row can cost the next
+fn filter_trials() -> Vec<(Compression, RowFilter)> {
+    let filters = [RowFilter::NoFilter, RowFilter::Adaptive, RowFilter::Paeth, RowFilter::Sub, RowFilter::Up, RowFilter::Avg];
+    [Compression::Best, Compression::Default].iter()
+        .flat_map(|compression| filters.iter().map(|filter| (*compression, *filter)))
+        .collect()
+}
+
+/// Images with more pixels than this are resized on several threads when `big_image_threads` is set
+pub const BIG_IMAGE_PIXELS: u64 = 16_000_000;
+
+/// Resizes and encodes `loaded_image` into a temporary file in `temp_dir`
+pub fn encode_image(loaded_image: &DynamicImage, nwidth: u32, nheight: u32, temp_dir: &Path, opts: &CompressOptions) -> Result<NamedTempFile, SquashError> {
+    let mut temp_path = NamedTempFile::new_in(temp_dir)?;
+    temp_path.write_all(&encode_to_vec(loaded_image, nwidth, nheight, opts)?)?;
+    Ok(temp_path)
+}
+
+/// Resizes and encodes `loaded_image` in memory
+fn encode_to_vec(loaded_image: &DynamicImage, nwidth: u32, nheight: u32, opts: &CompressOptions) -> Result<Vec<u8>, SquashError> {
+        let pixels = loaded_image.width() as u64 * loaded_image.height() as u64;
+        let filter = convert_filter(resolve_filter(loaded_image, opts));
+        let smaller_image = match opts.big_image_threads {
+            // Already within the limits, so there's nothing to resample
+            _ if loaded_image.dimensions() == (nwidth, nheight) => loaded_image.clone(),
+            Some(threads) if pixels > BIG_IMAGE_PIXELS => resize::resize(loaded_image, nwidth, nheight, filter, threads),
+            _ => loaded_image.resize_exact(nwidth, nheight, filter),
+        };
+        let smaller_image = match opts.sharpen {
+            Some(sigma) if sigma > 0.0 && smaller_image.dimensions() != loaded_image.dimensions() => smaller_image.unsharpen(sigma, 0),
+            _ => smaller_image,
+        };
+        let mut smaller_image = pad(smaller_image, opts);
+        if let Some(levels) = opts.posterize {
+            posterize::posterize(&mut smaller_image, levels, opts.dither.unwrap_or(Dither::None));
+        }
+        match opts.format {
+            Format::Png => {
+                let indexed = opts.quantize.map(|colors| quantize::quantize(&smaller_image, colors, opts.dither.unwrap_or(Dither::FloydSteinberg)));
+                let encode = |compression, row_filter| match &indexed {
+                    Some(indexed) => indexed.encode(compression, row_filter),
+                    None => encode_png(&smaller_image, compression, row_filter),
+                };
+                let mut smallest = encode(opts.compression, opts.row_filter)?;
+                // The settings behind `smallest`, which the interlaced re-encode keeps
+                let mut settings = (opts.compression, opts.row_filter);
+                let trials = if opts.try_all_filters { filter_trials() } else { vec![] };
+                for (compression, row_filter) in trials {
+                    let candidate = encode(compression, row_filter)?;
+                    if candidate.len() < smallest.len() {
+                        smallest = candidate;
+                        settings = (compression, row_filter);
+                    }
+                }
+                if opts.interlace == Interlace::Adam7 {
+                    smallest = interlace::adam7(&smallest, settings.0, settings.1)?;
+                }
+                Ok(smallest)
+            },
+            format => {
+                let mut encoded = vec![];
+                let image = to_8bit(smaller_image);
+                match format {
+                    Format::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut encoded)),
+                    Format::Avif => image.write_with_encoder(AvifEncoder::new(&mut encoded)),
+                    _ => image.write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, opts.quality)),
+                }.map_err(SquashError::encode)?;
+                Ok(encoded)
+            },
+        }
+}
+
+/// `dimensions` rounded up to the next multiple of `pad_to_multiple` on each axis, which is what images resized to
+/// them come out as
+pub fn padded_dimensions((width, height): (u32, u32), opts: &CompressOptions) -> (u32, u32) {
+    match opts.pad_to_multiple {
+        Some(multiple) => (width.next_multiple_of(multiple), height.next_multiple_of(multiple)),
+        None => (width, height),
+    }
+}
+
+/// Pads `image` on the right and bottom to its `padded_dimensions`, with transparent pixels, or the background color
+/// for images without alpha
+pub(crate) fn pad(image: DynamicImage, opts: &CompressOptions) -> DynamicImage {
+    let (width, height) = padded_dimensions(image.dimensions(), opts);
+    if (width, height) == image.dimensions() {
+        return image;
+    }
+    let mut canvas = DynamicImage::new(width, height, image.color());
+    if !image.color().has_alpha() {
+        let [red, green, blue] = opts.background;
+        for (x, y) in (0..height).flat_map(|y| (0..width).map(move |x| (x, y))) {
+            canvas.put_pixel(x, y, Rgba([red, green, blue, 255]));
+        }
+    }
+    imageops::replace(&mut canvas, &image, 0, 0);
+    canvas
+}
+
+/// How many extra encodes `encode_to_target` may spend looking for a scale that fits
+const TARGET_SIZE_ITERATIONS: usize = 8;
+/// `encode_to_target` won't shrink either axis below this many pixels
+const TARGET_SIZE_MIN_DIMENSION: u32 = 16;
+
+/// Like `encode_image`, but binary searches for the largest scale of `nwidth`x`nheight` that encodes to at most
+/// `target` bytes. Falls back to the smallest encode tried when nothing fits. Also returns the dimensions used
+fn encode_to_target(file_path: &Path, loaded_image: &DynamicImage, nwidth: u32, nheight: u32, target: u64, temp_dir: &Path, opts: &CompressOptions) -> Result<(NamedTempFile, (u32, u32)), SquashError> {
+    let encoded = encode_image(loaded_image, nwidth, nheight, temp_dir, opts)?;
+    let encoded_size = fs::metadata(encoded.path())?.len();
+    if encoded_size <= target {
+        return Ok((encoded, (nwidth, nheight)));
+    }
+    let mut smallest = (encoded, (nwidth, nheight), encoded_size);
+    let mut fitting = None;
+    let (mut low, mut high) = (0.0_f32, 1.0_f32);
+    for _ in 0..TARGET_SIZE_ITERATIONS {
+        let scale = (low + high) / 2.0;
+        let dims = (
+            ((nwidth as f32 * scale).round() as u32).max(TARGET_SIZE_MIN_DIMENSION.min(nwidth)),
+            ((nheight as f32 * scale).round() as u32).max(TARGET_SIZE_MIN_DIMENSION.min(nheight)),
+        );
+        let encoded = encode_image(loaded_image, dims.0, dims.1, temp_dir, opts)?;
+        let encoded_size = fs::metadata(encoded.path())?.len();
+        debug!("{}: target size trial at {}x{}: {} bytes", file_path.display(), dims.0, dims.1, encoded_size);
+        if encoded_size <= target {
+            low = scale;
+            fitting = Some((encoded, dims));
+        } else {
+            high = scale;
+            let floored = dims == (TARGET_SIZE_MIN_DIMENSION.min(nwidth), TARGET_SIZE_MIN_DIMENSION.min(nheight));
+            if encoded_size < smallest.2 {
+                smallest = (encoded, dims, encoded_size);
+            }
+            if floored {
+                break;
+            }
+        }
+    }
+    Ok(fitting.unwrap_or((smallest.0, smallest.1)))
+}
+
+/// Where to encode `output`: its own directory, so the final rename stays on one filesystem and is atomic. The
+/// directory is created if it doesn't exist yet, as with a fresh `--out-dir`. Dry runs never rename anything and
+/// shouldn't create directories, so they use the system temp dir
+fn temp_dir_for(output: &Path, opts: &CompressOptions) -> std::io::Result<PathBuf> {
+    let dir = match output.parent() {
+        _ if opts.dry_run => return Ok(env::temp_dir()),
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Wait before the first retry of a filesystem operation, doubled for each one after
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Errors that tend to clear up on their own, as on flaky network mounts. Missing files and denied permissions don't
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::ResourceBusy | io::ErrorKind::StaleNetworkFileHandle)
+}
+
+/// Runs `operation` on `path`, trying up to `retries` more times with exponential backoff while it fails transiently
+fn retry<T>(retries: u32, path: &Path, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                warn!("{}: {}, retrying in {}ms", path.display(), e, delay.as_millis());
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Gives `path` the mode, and on Unix the owner and group, of `reference`
+fn copy_permissions(reference: &fs::Metadata, path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{chown, MetadataExt};
+        // Only root can give a file away, but a group we're in is still worth keeping
+        if let Err(e) = chown(path, Some(reference.uid()), Some(reference.gid())) {
+            debug!("{}: couldn't copy the owner ({}), trying just the group", path.display(), e);
+            if let Err(e) = chown(path, None, Some(reference.gid())) {
+                debug!("{}: couldn't copy the group: {}", path.display(), e);
+            }
+        }
+    }
+    // After chown, which clears setuid and setgid
+    fs::set_permissions(path, reference.permissions())
+}
+
+/// True if a `size` byte re-encode is worth replacing a `baseline` byte file with, going by `force` and
+/// `overwrite_threshold`
+fn beats(size: u64, baseline: u64, opts: &CompressOptions) -> bool {
+    opts.force || size * 100 <= baseline * (100 - opts.overwrite_threshold.min(100) as u64)
+}
+
+/// Decodes the `encoded` image in full and checks it came out `dimensions` in size
+fn verify(encoded: &[u8], dimensions: (u32, u32)) -> Result<(), SquashError> {
+    let decoded = ImageReader::new(Cursor::new(encoded)).with_guessed_format()?.decode().map_err(|e| SquashError::Verification(e.to_string()))?;
+    if decoded.dimensions() != dimensions {
+        return Err(SquashError::Verification(format!("decoded as {}x{} instead of {}x{}", decoded.width(), decoded.height(), dimensions.0, dimensions.1)));
+    }
+    Ok(())
+}
+
+/// True if `output` looks like a finished result for `input`: written no earlier than `input` last changed, and
+/// decoding in full at `input`'s own size or within the size `opts` resizes it to. Anything else is left over from an
+/// interrupted run, or out of date
+pub fn is_finished_output(input: &Path, output: &Path, opts: &CompressOptions) -> bool {
+    let finished = || -> Result<bool, Box<dyn Error>> {
+        if fs::metadata(output)?.modified()? < fs::metadata(input)?.modified()? {
+            return Ok(false);
+        }
+        // image can't decode avif, so those only have to be there
+        if opts.format == Format::Avif {
+            return Ok(fs::metadata(output)?.len() > 0);
+        }
+        let (width, height) = ImageReader::open(input)?.with_guessed_format()?.into_dimensions()?;
+        let (max_width, max_height) = padded_dimensions(target_dimensions(width, height, opts), opts);
+        let decoded = ImageReader::open(output)?.with_guessed_format()?.decode()?;
+        Ok(decoded.dimensions() == (width, height) || (decoded.width() <= max_width && decoded.height() <= max_height))
+    };
+    finished().unwrap_or(false)
+}
+
+/// Fails once the caller has set `opts.abandoned`, so nothing gets written for a file it has given up on
+fn check_abandoned(opts: &CompressOptions) -> Result<(), SquashError> {
+    match &opts.abandoned {
+        Some(abandoned) if abandoned.load(Ordering::Relaxed) => Err(SquashError::Abandoned),
+        _ => Ok(()),
+    }
+}
+
+/// Moves `encoded` over `outfile_name` unless it doesn't beat `baseline`. The result keeps the permissions of the file
+/// it replaces, or of `source` when there wasn't one. With `verify`, it has to decode as a `dimensions` sized image
+/// first. Returns the size `outfile_name` ends up with, or would outside a dry run
+fn replace_if_smaller(encoded: NamedTempFile, outfile_name: &Path, source: &Path, dimensions: (u32, u32), baseline: Option<u64>, opts: &CompressOptions) -> Result<u64, SquashError> {
+        let temp_size = fs::metadata(encoded.path())?.len();
+        if let Some(baseline) = baseline && !beats(temp_size, baseline, opts) {
+            return Ok(baseline);
+        }
+        // image can't decode avif, so those go unchecked
+        if opts.verify && opts.format != Format::Avif {
+            verify(&fs::read(encoded.path())?, padded_dimensions(dimensions, opts))?;
+        }
+        if opts.dry_run {
+            return Ok(temp_size);
+        }
+        check_abandoned(opts)?;
+
+        let original_metadata = fs::metadata(outfile_name).ok();
+        // Only Windows refuses to rename over a read-only file
+        #[cfg(windows)]
+        {
+            if let Some(metadata) = &original_metadata && metadata.permissions().readonly() {
+                let mut writable = metadata.permissions();
+                writable.set_readonly(false);
+                retry(opts.retries, outfile_name, || fs::set_permissions(outfile_name, writable.clone()))?;
+            }
+        }
+        // Temp files are private to us, so fix that up before the rename makes it visible
+        if let Some(reference) = original_metadata.or_else(|| fs::metadata(source).ok()) {
+            retry(opts.retries, encoded.path(), || copy_permissions(&reference, encoded.path()))?;
+        }
+        retry(opts.retries, outfile_name, || fs::rename(encoded.path(), outfile_name))?;
+        Ok(temp_size)
+}
+
+/// Resizes and encodes `loaded_image`, replacing `outfile_name` if the result is smaller. Returns the resulting size
+pub fn compress_image(loaded_image: DynamicImage, outfile_name: &Path, nwidth: u32, nheight: u32, opts: &CompressOptions) -> Result<u64, SquashError> {
+    let encoded = encode_image(&loaded_image, nwidth, nheight, &temp_dir_for(outfile_name, opts)?, opts)?;
+    let baseline = fs::metadata(outfile_name).ok().map(|metadata| metadata.len());
+    replace_if_smaller(encoded, outfile_name, outfile_name, (nwidth, nheight), baseline, opts)
+}
+
+fn set_file_times(path: &Path, times: FileTimes) -> std::io::Result<()> {
+    // Windows needs write-attributes access, which a plain read handle doesn't have
+    #[cfg(windows)]
+    let file = {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+        fs::OpenOptions::new().access_mode(FILE_WRITE_ATTRIBUTES).open(path)?
+    };
+    #[cfg(not(windows))]
+    let file = fs::File::open(path)?;
+    file.set_times(times)
+}
+
+/// Works out the size an image should be resized to
+pub fn target_dimensions(width: u32, height: u32, opts: &CompressOptions) -> (u32, u32) {
+    let (width, height) = limited_dimensions(width, height, opts);
+    let (width, height) = if opts.scale == 1.0 {
+        (width, height)
+    } else {
+        (((width as f32 * opts.scale).round() as u32).max(1), ((height as f32 * opts.scale).round() as u32).max(1))
+    };
+    match opts.power_of_two {
+        Some(power_of_two) => (power_of_two.snap(width, opts.max_width), power_of_two.snap(height, opts.max_height)),
+        None => (width, height),
+    }
+}
+
+/// The size an image is resized to by `percent` or the size limits
+fn limited_dimensions(width: u32, height: u32, opts: &CompressOptions) -> (u32, u32) {
+    if let Some(percent) = opts.percent {
+        let scale = percent as f32 / 100.0;
+        return (((width as f32 * scale).round() as u32).max(1), ((height as f32 * scale).round() as u32).max(1));
+    }
+    // Ratios are capped at 1 so images are only ever shrunk
+    let w_ratio = opts.max_width.map_or(1.0, |max_w| (max_w as f32 / width as f32).min(1.0));
+    let h_ratio = opts.max_height.map_or(1.0, |max_h| (max_h as f32 / height as f32).min(1.0));
+    let ratio = w_ratio.min(h_ratio);
+    let pixels = width as f64 * height as f64;
+    if let Some(max_pixels) = opts.max_pixels && (max_pixels as f64 / pixels).sqrt() < ratio as f64 {
+        // Rounding down keeps the product within the budget, which rounding to nearest wouldn't
+        let ratio = (max_pixels as f64 / pixels).sqrt();
+        return (((width as f64 * ratio) as u32).max(1), ((height as f64 * ratio) as u32).max(1));
+    }
+    // Extreme aspect ratios can round an axis down to nothing, which `resize` can't handle
+    (((width as f32 * ratio).round() as u32).max(1), ((height as f32 * ratio).round() as u32).max(1))
+}
+
+/// What `decode_file` made of an input, for `encode_file` to finish
+pub struct Decoded {
+    input_metadata: fs::Metadata,
+    dimensions: (u32, u32),
+    source: Source,
+    /// Chunks to carry over to png output
+    metadata: Vec<chunks::Chunk>,
+    phys: Option<chunks::Chunk>,
+}
+
+impl Decoded {
+    fn filtered(input_metadata: fs::Metadata, stats: CompressStats) -> Decoded {
+        Decoded { input_metadata, dimensions: (0, 0), source: Source::Filtered(stats), metadata: vec![], phys: None }
+    }
+}
+
+enum Source {
+    /// Left alone without being decoded, for the reason logged at the time
+    Filtered(CompressStats),
+    /// An APNG, which `apng::encode` decodes a frame at a time itself
+    Animated,
+    /// The candidates `load_and_preprocess` came up with
+    Still(Vec<DynamicImage>),
+}
+
+/// Compresses `input` into `output`, which may be the same path
+pub fn compress_file(input: &Path, output: &Path, opts: &CompressOptions) -> Result<CompressStats, SquashError> {
+    encode_file(decode_file(input, opts)?, input, output, opts)
+}
+
+/// The first half of `compress_file`: checks `input` against the filters and decodes it. This is where the memory
+/// goes, so it can be run fewer at a time than `encode_file`
+pub fn decode_file(input: &Path, opts: &CompressOptions) -> Result<Decoded, SquashError> {
+    let input_metadata = fs::metadata(input)?;
+    let original_size = input_metadata.len();
+    if original_size < opts.min_size {
+        info!("{}: {} bytes is below the minimum size, skipping", input.display(), original_size);
+        return Ok(Decoded::filtered(input_metadata, CompressStats::filtered(original_size)));
+    }
+    // Only the header is read, so images outside the dimension limits cost next to nothing
+    let (width, height) = ImageReader::open(input)?.with_guessed_format()?.into_dimensions().map_err(SquashError::decode)?;
+    if width == 0 || height == 0 {
+        return Err(SquashError::ZeroDimension(width, height));
+    }
+    let longer = width.max(height);
+    if opts.min_dimension.is_some_and(|min| longer < min) || opts.max_dimension.is_some_and(|max| longer > max) {
+        info!("{}: {}x{} is outside the dimension limits, skipping", input.display(), width, height);
+        return Ok(Decoded::filtered(input_metadata, CompressStats { original_dimensions: Some((width, height)), ..CompressStats::filtered(original_size) }));
+    }
+    // Another format, borders to trim or a --target-size still to meet needs the re-encode either way
+    if opts.skip_within_limits && !opts.force && !opts.trim && opts.format == Format::Png && padded_dimensions(target_dimensions(width, height, opts), opts) == (width, height)
+        && opts.target_size.is_none_or(|target| original_size <= target) {
+        info!("{}: {}x{} is already within the size limits, skipping", input.display(), width, height);
+        return Ok(Decoded::filtered(input_metadata, CompressStats { original_dimensions: Some((width, height)), ..CompressStats::filtered(original_size) }));
+    }
+    if opts.animated_gifs == AnimatedGifs::Skip && InputFormat::sniff(input) == Some(InputFormat::Gif) && is_animated_gif(input)? {
+        warn!("{}: is animated, skipping. --animated-gifs first-frame squashes its first frame instead", input.display());
+        return Ok(Decoded::filtered(input_metadata, CompressStats { original_dimensions: Some((width, height)), ..CompressStats::filtered(original_size) }));
+    }
+    let animated_png = InputFormat::sniff(input) == Some(InputFormat::Png) && apng::is_animated(input)?;
+    if animated_png && !opts.apng {
+        warn!("{}: is an animated png, skipping. --apng squashes every frame instead", input.display());
+        return Ok(Decoded::filtered(input_metadata, CompressStats { original_dimensions: Some((width, height)), ..CompressStats::filtered(original_size) }));
+    }
+    if animated_png && opts.format != Format::Png {
+        warn!("{}: is an animated png, which {:?} output can't hold, skipping", input.display(), opts.format);
+        return Ok(Decoded::filtered(input_metadata, CompressStats { original_dimensions: Some((width, height)), ..CompressStats::filtered(original_size) }));
+    }
+    let source_chunks = if opts.strip_all || opts.format != Format::Png { vec![] } else { chunks::read(input)? };
+    // Animations aren't turned upright, so they keep their orientation as it is
+    let (metadata, phys) = carried_chunks(&source_chunks, opts.auto_orient && !animated_png, opts);
+    let source = if animated_png { Source::Animated } else { Source::Still(load_and_preprocess(input, opts)?) };
+    Ok(Decoded { input_metadata, dimensions: (width, height), source, metadata, phys })
+}
+
+/// The chunks of a source png to carry over to its output: text, EXIF and color profile as `opts` allow, and its pHYs.
+/// When `oriented`, the pixels were turned upright by the EXIF orientation, so the EXIF carried over says they're upright
+/// and the pHYs swaps its axes if they were turned on their side
+fn carried_chunks(source_chunks: &[chunks::Chunk], oriented: bool, opts: &CompressOptions) -> (Vec<chunks::Chunk>, Option<chunks::Chunk>) {
+    let metadata = source_chunks.iter().filter(|chunk| {
+        (!opts.strip_metadata && (chunks::TEXT.contains(&chunk.kind) || chunk.kind == chunks::EXIF)) || (!opts.strip_icc && chunk.kind == chunks::ICC)
+    }).map(|chunk| if chunk.kind == chunks::EXIF && oriented {
+        chunks::Chunk { kind: chunks::EXIF, data: exif::reset_orientation(&chunk.data) }
+    } else {
+        chunk.clone()
+    }).collect();
+    let sideways = oriented && matches!(exif_orientation(source_chunks),
+        Some(Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH));
+    let phys = source_chunks.iter().find(|chunk| chunk.kind == chunks::PHYS);
+    (metadata, if sideways { phys.and_then(chunks::turn_phys) } else { phys.cloned() })
+}
+
+/// `metadata` plus a pHYs for an image resized `from` one size `to` another: the --dpi one, or `phys` rescaled. Also
+/// the gAMA the pixels were converted to by --normalize-gamma
+fn extra_chunks(mut metadata: Vec<chunks::Chunk>, phys: Option<&chunks::Chunk>, from: (u32, u32), to: (u32, u32), opts: &CompressOptions) -> Vec<chunks::Chunk> {
+    metadata.extend(match opts.dpi {
+        Some(dpi) => Some(chunks::phys_for_dpi(dpi)),
+        None => phys.and_then(|phys| chunks::rescale_phys(phys, from, to)),
+    });
+    metadata.extend(opts.normalize_gamma.map(chunks::gama_for));
+    metadata
+}
+
+/// The second half of `compress_file`: resizes and encodes what `decode_file` made of `input` into `output`
+pub fn encode_file(decoded: Decoded, input: &Path, output: &Path, opts: &CompressOptions) -> Result<CompressStats, SquashError> {
+    let Decoded { input_metadata, dimensions: (width, height), source, metadata, phys } = decoded;
+    let original_size = input_metadata.len();
+    let (animated_png, images) = match source {
+        Source::Filtered(stats) => return Ok(stats),
+        Source::Animated => (true, vec![]),
+        Source::Still(images) => (false, images),
+    };
+    let writes_phys = opts.format == Format::Png && (opts.dpi.is_some() || phys.is_some());
+    let writes_gamma = opts.format == Format::Png && opts.normalize_gamma.is_some();
+    if let (Some(suffix), false, Ok(true)) = (&opts.backup, opts.dry_run, fs::exists(output)) {
+        check_abandoned(opts)?;
+        let mut backup_name = output.as_os_str().to_owned();
+        backup_name.push(suffix);
+        if !opts.overwrite_backups && fs::exists(&backup_name)? {
+            return Err(SquashError::BackupExists(backup_name.into()));
+        }
+        fs::copy(output, &backup_name)?;
+    }
+    let temp_dir = temp_dir_for(output, opts)?;
+    let mut smallest: Option<Candidate> = None;
+    // Size of the image the winner was resized from, which --trim makes smaller than the input
+    let mut resized_from = (width, height);
+    if animated_png {
+        // Every frame goes into the one encode, so there's a single candidate and no target size search
+        let candidate = apng::encode(input, &temp_dir, opts)?;
+        debug!("{}: animation -> {}x{} encoded to {} bytes", input.display(), candidate.dimensions.0, candidate.dimensions.1, candidate.size);
+        smallest = Some(candidate);
+    } else {
+        for loaded_image in images {
+            let (nwidth, nheight) = target_dimensions(loaded_image.width(), loaded_image.height(), opts);
+
+            // Every candidate is encoded up front so only the smallest one is ever written
+            let (encoded, (nwidth, nheight)) = match opts.target_size {
+                Some(target) => {
+                    // Leave room for the metadata chunks added afterwards
+                    let metadata_size = metadata.iter().map(|chunk| chunk.data.len() as u64 + 12).sum::<u64>() + if writes_phys { 21 } else { 0 } + if writes_gamma { 16 } else { 0 };
+                    encode_to_target(input, &loaded_image, nwidth, nheight, target.saturating_sub(metadata_size), &temp_dir, opts)?
+                },
+                None => (encode_image(&loaded_image, nwidth, nheight, &temp_dir, opts)?, (nwidth, nheight)),
+            };
+            let encoded_size = fs::metadata(encoded.path())?.len();
+            debug!("{}: {:?} candidate {}x{} -> {}x{} encoded to {} bytes", input.display(), loaded_image.color(), loaded_image.width(), loaded_image.height(), nwidth, nheight, encoded_size);
+            if smallest.as_ref().is_none_or(|smallest| encoded_size < smallest.size) {
+                resized_from = loaded_image.dimensions();
+                smallest = Some(Candidate {
+                    encoded,
+                    size: encoded_size,
+                    color: loaded_image.color(),
+                    dimensions: (nwidth, nheight),
+                    filter: resolve_filter(&loaded_image, opts),
+                });
+            }
+        }
+    }
+    // The encoder only writes pixel data, so carry the source's text and color profile over to the winner, along with
+    // its physical size, which takes a new pHYs once the pixels are resized
+    if let Some(candidate) = &mut smallest && opts.format == Format::Png && (opts.strip_all || !metadata.is_empty() || writes_phys || writes_gamma) {
+        let extra = extra_chunks(metadata, phys.as_ref(), resized_from, candidate.dimensions, opts);
+        candidate.size = chunks::rewrite(candidate.encoded.path(), |chunk| !opts.strip_all || chunks::is_essential(chunk), &extra)?;
+        debug!("{}: kept {} metadata chunks, {} bytes with them", input.display(), extra.len(), candidate.size);
+    }
+    if let Some(candidate) = &smallest {
+        let outcome = if opts.dry_run {
+            "dry run, nothing written"
+        } else if beats(candidate.size, original_size, opts) {
+            "replacing"
+        } else {
+            "keeping the original"
+        };
+        info!("{}: picked {:?} at {}x{}, {} bytes vs {} original, {}", input.display(), candidate.color, candidate.dimensions.0, candidate.dimensions.1, candidate.size, original_size, outcome);
+    }
+
+    let new_dimensions = smallest.as_ref().map(|candidate| padded_dimensions(candidate.dimensions, opts));
+    let filter = smallest.as_ref().map(|candidate| candidate.filter);
+    // A scaled variant is a different image than the input, so only an earlier write of the same variant can beat it
+    let baseline = if opts.scale == 1.0 { Some(original_size) } else { fs::metadata(output).ok().map(|metadata| metadata.len()) };
+    let projected_size = match smallest {
+        Some(candidate) if opts.force => replace_if_smaller(candidate.encoded, output, input, candidate.dimensions, None, opts)?,
+        Some(candidate) => replace_if_smaller(candidate.encoded, output, input, candidate.dimensions, baseline, opts)?,
+        None => original_size,
+    };
+    let kept_original = opts.scale == 1.0 && projected_size == original_size && !opts.force;
+    // Nothing beat the original, so a separate output of the same type just gets a copy of it
+    if kept_original && input != output && input.extension() == output.extension() && !opts.dry_run {
+        check_abandoned(opts)?;
+        fs::copy(input, output)?;
+    }
+    if opts.preserve_mtime && !opts.dry_run && fs::exists(output)? {
+        let times = FileTimes::new().set_accessed(input_metadata.accessed()?).set_modified(input_metadata.modified()?);
+        set_file_times(output, times)?;
+    }
+
+    let new_dimensions = if kept_original { Some((width, height)) } else { new_dimensions };
+    Ok(CompressStats {
+        original_size,
+        new_size: projected_size,
+        skipped: kept_original,
+        filtered: false,
+        over_target: opts.target_size.is_some_and(|target| projected_size > target),
+        original_dimensions: Some((width, height)),
+        new_dimensions,
+        filter: filter.filter(|_| new_dimensions != Some((width, height))),
+    })
+}
+
+/// `compress_file` for an image already in memory, e.g. an upload. Runs the same decode, resize and encode, and returns
+/// the result, or `input` itself when nothing beats it. Only the options to do with pixels and metadata apply: there
+/// are no files to filter, back up or stamp, and no --target-size search. Animated pngs come back untouched
+pub fn compress_bytes(input: &[u8], opts: &CompressOptions) -> Result<Vec<u8>, SquashError> {
+    let source_chunks = chunks::parse(input);
+    if source_chunks.iter().any(|chunk| &chunk.kind == b"acTL") {
+        debug!("<bytes>: is an animated png, leaving it as it is");
+        return Ok(input.to_vec());
+    }
+    let loaded_image = ImageReader::new(Cursor::new(input)).with_guessed_format()?.decode().map_err(SquashError::decode)?;
+    let (width, height) = loaded_image.dimensions();
+    if width == 0 || height == 0 {
+        return Err(SquashError::ZeroDimension(width, height));
+    }
+    let (metadata, phys) = if opts.strip_all || opts.format != Format::Png { (vec![], None) } else { carried_chunks(&source_chunks, opts.auto_orient, opts) };
+
+    // The winning encode, its size and the size it was resized from
+    let mut smallest = None;
+    for loaded_image in preprocess(loaded_image, &source_chunks, "<bytes>", opts) {
+        let (nwidth, nheight) = target_dimensions(loaded_image.width(), loaded_image.height(), opts);
+        let encoded = encode_to_vec(&loaded_image, nwidth, nheight, opts)?;
+        if smallest.as_ref().is_none_or(|(smallest, _, _): &(Vec<u8>, _, _)| encoded.len() < smallest.len()) {
+            smallest = Some((encoded, (nwidth, nheight), loaded_image.dimensions()));
+        }
+    }
+    let Some((mut encoded, dimensions, resized_from)) = smallest else {
+        return Ok(input.to_vec());
+    };
+    if opts.format == Format::Png && (opts.strip_all || !metadata.is_empty() || opts.dpi.is_some() || phys.is_some() || opts.normalize_gamma.is_some()) {
+        let extra = extra_chunks(metadata, phys.as_ref(), resized_from, dimensions, opts);
+        encoded = chunks::rewrite_bytes(&encoded, |chunk| !opts.strip_all || chunks::is_essential(chunk), &extra);
+    }
+    if !beats(encoded.len() as u64, input.len() as u64, opts) {
+        return Ok(input.to_vec());
+    }
+    // image can't decode avif, so those go unchecked
+    if opts.verify && opts.format != Format::Avif {
+        verify(&encoded, padded_dimensions(dimensions, opts))?;
+    }
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `image` as a png named `name` in `dir`, with `extra` chunks after its IHDR
+    fn write_png(dir: &Path, name: &str, image: &DynamicImage, extra: &[chunks::Chunk]) -> PathBuf {
+        let path = dir.join(name);
+        let encoded = encode_png(image, Compression::Fast, RowFilter::Adaptive).unwrap();
+        fs::write(&path, chunks::rewrite_bytes(&encoded, |_| true, extra)).unwrap();
+        path
+    }
+
+    #[test]
+    fn small_images_are_not_upscaled() {
+        let opts = CompressOptions { max_width: Some(4096), max_height: Some(4096), ..Default::default() };
+        assert_eq!(limited_dimensions(64, 32, &opts), (64, 32));
+        assert_eq!(target_dimensions(64, 32, &opts), (64, 32));
+    }
+
+    #[test]
+    fn extreme_aspect_ratios_keep_both_axes() {
+        let opts = CompressOptions { max_width: Some(4), max_height: Some(4), ..Default::default() };
+        let (width, height) = limited_dimensions(3000, 2, &opts);
+        assert!(width >= 1 && height >= 1);
+        assert!(width <= 4 && height <= 4);
+        let (width, height) = limited_dimensions(2, 3000, &opts);
+        assert!(width >= 1 && height >= 1);
+    }
+
+    #[test]
+    fn strip_all_leaves_only_critical_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = chunks::Chunk { kind: *b"tEXt", data: b"Author\0someone".to_vec() };
+        let gama = chunks::gama_for(2.2);
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| Rgb([x as u8 * 8, y as u8 * 8, 0])));
+        let input = write_png(dir.path(), "in.png", &image, &[text, chunks::phys_for_dpi(300), gama]);
+        let output = dir.path().join("out.png");
+        let opts = CompressOptions { strip_all: true, force: true, ..Default::default() };
+        compress_file(&input, &output, &opts).unwrap();
+        let kinds: Vec<[u8; 4]> = chunks::read(&output).unwrap().iter().map(|chunk| chunk.kind).collect();
+        assert!(!kinds.is_empty());
+        assert!(kinds.iter().all(|kind| kind[0].is_ascii_uppercase()), "{:?}", kinds);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_inputs_stay_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 0])));
+        let png = write_png(dir.path(), "read_only.png", &image, &[]);
+        fs::set_permissions(&png, fs::Permissions::from_mode(0o444)).unwrap();
+        let opts = CompressOptions { max_width: Some(16), force: true, ..Default::default() };
+        let stats = compress_file(&png, &png, &opts).unwrap();
+        assert_eq!(stats.new_dimensions, Some((16, 16)));
+        assert_eq!(fs::metadata(&png).unwrap().permissions().mode() & 0o777, 0o444);
+    }
+
+    #[test]
+    fn bit_depth_eight_writes_8_bit_grayscale() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(32, 32, |x, y| image::Luma([(x * 2000 + y * 7) as u16])));
+        let input = write_png(dir.path(), "gray16.png", &image, &[]);
+        let output = dir.path().join("gray8.png");
+        let opts = CompressOptions { bit_depth: BitDepth::Eight, force: true, ..Default::default() };
+        compress_file(&input, &output, &opts).unwrap();
+        assert_eq!(ImageReader::open(&output).unwrap().decode().unwrap().color(), ColorType::L8);
+    }
+
+    /// The pHYs of the png at `path` as pixels per unit on each axis
+    fn phys_of(path: &Path) -> (u32, u32) {
+        let chunks = chunks::read(path).unwrap();
+        let data = &chunks.iter().find(|chunk| chunk.kind == chunks::PHYS).unwrap().data;
+        (u32::from_be_bytes(data[0..4].try_into().unwrap()), u32::from_be_bytes(data[4..8].try_into().unwrap()))
+    }
+
+    #[test]
+    fn power_of_two_rescales_phys_per_axis() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 100, |x, y| Rgb([x as u8, y as u8, 0])));
+        let input = write_png(dir.path(), "wide.png", &image, &[chunks::phys_for_dpi(300)]);
+        let output = dir.path().join("snapped.png");
+        let opts = CompressOptions { power_of_two: Some(PowerOfTwo::Nearest), force: true, ..Default::default() };
+        let stats = compress_file(&input, &output, &opts).unwrap();
+        assert_eq!(stats.new_dimensions, Some((256, 128)));
+        let (x, y) = phys_of(&input);
+        assert_eq!(phys_of(&output), ((x as f64 * 256.0 / 300.0).round() as u32, (y as f64 * 128.0 / 100.0).round() as u32));
+    }
+
+    #[test]
+    fn turning_sideways_swaps_phys_axes() {
+        let dir = tempfile::tempdir().unwrap();
+        // Big endian TIFF with a single IFD entry: orientation 6, a quarter turn clockwise
+        let exif = b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0".to_vec();
+        let phys = chunks::Chunk { kind: chunks::PHYS, data: [&3000u32.to_be_bytes()[..], &6000u32.to_be_bytes(), &[1]].concat() };
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 32, |x, y| Rgb([x as u8 * 4, y as u8 * 8, 0])));
+        let input = write_png(dir.path(), "sideways.png", &image, &[chunks::Chunk { kind: chunks::EXIF, data: exif }, phys]);
+        let output = dir.path().join("upright.png");
+        let opts = CompressOptions { force: true, ..Default::default() };
+        let stats = compress_file(&input, &output, &opts).unwrap();
+        assert_eq!(stats.new_dimensions, Some((32, 64)));
+        assert_eq!(phys_of(&output), (6000, 3000));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{fs::{self, File, OpenOptions, TryLockError}, io, path::{Path, PathBuf}, thread, time::Duration};
+
+use log::{info, warn};
+
+use crate::interrupt;
+
+/// Name of the lockfile kept in each directory while a run works in it
+pub const LOCKFILE: &str = ".squasher.lock";
+/// How often --lock-wait checks whether the other run is done
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Advisory locks on the directories a run writes to, so overlapping runs don't rename over each other's files. The
+/// lockfiles are removed again when this is dropped. The operating system lets go of the locks when the process exits,
+/// however it exits, so a killed run leaves a lockfile behind but never a stale lock
+pub struct Locks {
+    files: Vec<(PathBuf, File)>,
+}
+
+impl Drop for Locks {
+    fn drop(&mut self) {
+        // Removed while still locked, so a run that was waiting on one sees it's gone and locks the new one instead
+        for (path, file) in self.files.drain(..) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("{}: couldn't be removed: {}", path.display(), e);
+            }
+            drop(file);
+        }
+    }
+}
+
+/// True if `path` still names the file `file` has open. A run that held it removes it on the way out, and a lock on
+/// the removed file keeps nobody out
+fn is_current(file: &File, path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        // Elsewhere a file can't be removed while it's open, so it can't have changed under us
+        let _ = (file, path);
+        true
+    }
+}
+
+/// Locks every directory in `dirs`, which must exist. If another run holds one, waits for it when `wait` is set and
+/// fails otherwise. A directory the lockfile can't be created in, e.g. a read-only one, is warned about and left
+/// unlocked
+pub fn acquire<'a>(dirs: impl IntoIterator<Item = &'a Path>, wait: bool) -> Result<Locks, String> {
+    // Always taking them in the same order keeps two waiting runs from each holding what the other wants
+    let mut dirs: Vec<PathBuf> = dirs.into_iter().map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())).collect();
+    dirs.sort();
+    dirs.dedup();
+    // Built up as it goes, so failing partway still removes the lockfiles already made
+    let mut locks = Locks { files: vec![] };
+    for dir in dirs {
+        let path = dir.join(LOCKFILE);
+        let mut waiting = false;
+        let file = loop {
+            let file = match OpenOptions::new().create(true).truncate(false).write(true).open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("{}: couldn't be created, so this directory isn't locked against other runs: {}", path.display(), e);
+                    break None;
+                },
+            };
+            match file.try_lock() {
+                Ok(()) if is_current(&file, &path) => break Some(file),
+                // The run we waited on removed it as it finished, so start over with a fresh one
+                Ok(()) => continue,
+                Err(TryLockError::WouldBlock) if wait && !interrupt::interrupted() => {
+                    if !waiting {
+                        info!("{}: another run holds this lock, waiting for it to finish", path.display());
+                        waiting = true;
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                },
+                Err(TryLockError::WouldBlock) if wait => return Err("interrupted while waiting for another run to finish".to_string()),
+                Err(TryLockError::WouldBlock) => {
+                    return Err(format!("another run is already working in {} (it holds {}). Pass --lock-wait to wait for it instead", dir.display(), path.display()));
+                },
+                Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => {
+                    warn!("{}: the filesystem doesn't support locking, so this directory isn't locked against other runs", path.display());
+                    break Some(file);
+                },
+                Err(TryLockError::Error(e)) => return Err(format!("{}: couldn't be locked: {}", path.display(), e)),
+            }
+        };
+        locks.files.extend(file.map(|file| (path, file)));
+    }
+    Ok(locks)
+}
@@ -1,27 +1,39 @@
-use std::{env::set_current_dir, fs, os::windows::fs::MetadataExt, path::PathBuf, thread};
+use std::{borrow::Cow, cmp::Reverse, collections::{HashMap, HashSet}, ffi::OsString, fs, io::{self, Write}, iter, path::{self, Path, PathBuf}, process::ExitCode, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, mpsc, Arc, Mutex, RwLock}, thread, time::{Duration, SystemTime}};
 
-use clap::Parser;
-use image::{codecs::png::PngEncoder, imageops::{resize, FilterType::{self, Gaussian}}, DynamicImage, GenericImage, GenericImageView, ImageReader, Rgb, RgbImage};
-use tempfile::NamedTempFile;
+use clap::{Parser, ValueEnum};
+use log::{info, warn};
+use archive::Archive;
+use budget::{Budget, Reservation};
+use cache::Cache;
+use disk::DiskType;
+use progress::Progress;
+use rayon::{iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelBridge, ParallelIterator}, ThreadPool, ThreadPoolBuilder};
+use png_squasher::{decode_file, decoded_size, encode_file, find_png_paths, has_png_signature, is_finished_output, glob, json, AnimatedGifs, BitDepth, Compression, CompressOptions, CompressStats, Dither, Filter, Format, InputFormat, Interlace, PowerOfTwo, RowFilter, ScanOptions, SquashError};
 
-#[derive(clap::ValueEnum, Copy, Clone, Default, Debug)]
-enum Filter {
-    #[default]
-    Gaussian,
-    Lanczos,
-    CatmullRom,
-    NearestNeighbor,
-    LinearTriangle,
-}
+mod bench;
+mod archive;
+mod budget;
+mod cache;
+mod config;
+mod dedupe;
+mod disk;
+mod interrupt;
+mod lock;
+mod logger;
+mod preview;
+mod progress;
+mod stats;
 
-fn convert_filter(filter: Filter) -> FilterType {
-    match filter {
-        Filter::Gaussian => FilterType::Gaussian,
-        Filter::Lanczos => FilterType::Lanczos3,
-        Filter::CatmullRom => FilterType::CatmullRom,
-        Filter::NearestNeighbor => FilterType::Nearest,
-        Filter::LinearTriangle => FilterType::Triangle
-    }
+/// How often --watch rescans the search directory
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Order pngs are handed to the workers in
+#[derive(clap::ValueEnum, Copy, Clone, Debug)]
+enum SortOrder {
+    Name,
+    /// Biggest first, so the slowest pngs don't straggle at the end
+    SizeDesc,
+    SizeAsc,
 }
 
 /// A helper util that will search for pngs in the current directory tree and then compress them
@@ -35,129 +47,1451 @@ struct Args {
     #[arg(short, long)]
     y_max: Option<u32>,
 
-    /// Directory to start the recursive png search
-    #[arg(short, long)]
-    dir: Option<String>,
+    /// Maximum number of pixels pngs are allowed to have in total. Larger images will be scaled down evenly on both axes.
+    /// Accepts k, M and G suffixes, in thousands
+    #[arg(long, value_parser = parse_pixel_count)]
+    max_pixels: Option<u64>,
+
+    /// Scale every png to this percentage of its original size. Can't be combined with --x-max, --y-max or --max-pixels
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100), conflicts_with_all = ["x_max", "y_max", "max_pixels"])]
+    percent: Option<u8>,
+
+    /// Snap each side of every png to a power of two after the other size limits: `up` to the next one, `down` to the
+    /// one below, or the `nearest`. --x-max and --y-max still cap the result. Sides are snapped separately, so the
+    /// aspect ratio can change
+    #[arg(long, value_name = "MODE")]
+    power_of_two: Option<PowerOfTwo>,
+
+    /// Order to process pngs in. Defaults to the order they were found in
+    #[arg(long, value_enum)]
+    sort: Option<SortOrder>,
+
+    /// Only process this many pngs, picked at random from everything found, to try settings out on part of a big tree
+    #[arg(long, value_name = "N", conflicts_with = "watch")]
+    sample: Option<usize>,
+
+    /// Seed for --sample, so the same pngs get picked each time. The summary says which seed a run used
+    #[arg(long, requires = "sample")]
+    seed: Option<u64>,
 
+    /// Write each png at several scales instead, named like icon@0.5x.png. Takes factors like `1,0.5,0.25`, or
+    /// densities like `@1x,@2x,@3x` where the largest is the png's size after the other limits
+    #[arg(long, value_delimiter = ',', value_parser = parse_scale, value_name = "SCALES", conflicts_with = "cache")]
+    scales: Vec<(String, f32)>,
+
+    /// Shrink each png further, as little as possible, until it's at most this big. Accepts k, M and G suffixes
+    #[arg(long, value_parser = parse_size)]
+    target_size: Option<u64>,
+
+    /// Fail any png whose output still ends up bigger than this, after --target-size has had its go. Accepts k, M and
+    /// G suffixes
+    #[arg(long, value_parser = parse_size, value_name = "BYTES")]
+    output_max_bytes: Option<u64>,
+
+    /// Stop starting pngs once everything written adds up to this many bytes, working biggest first so the space goes
+    /// where it saves the most. The pngs left untouched are listed at the end. Pngs already in flight still finish, so
+    /// the total can end up a little over. Accepts k, M and G suffixes
+    #[arg(long, value_parser = parse_size, value_name = "BYTES", conflicts_with_all = ["sort", "watch"])]
+    max_total_bytes: Option<u64>,
+
+    /// Directory to start the recursive png search. Give it more than once to search several. Defaults to the current
+    /// directory
+    #[arg(short, long, num_args = 1..)]
+    dir: Vec<PathBuf>,
+
+    /// Resampling filter used when scaling pngs down
     #[arg(short, long, default_value_t, value_enum)]
-    filter: Filter
+    filter: Filter,
+
+    /// Use a different filter for pngs whose path, relative to the search directory, matches a glob, e.g.
+    /// `sprites/**=nearest`. May be repeated, and the first match wins. Everything else uses --filter
+    #[arg(long, value_parser = parse_filter_for, value_name = "GLOB=FILTER")]
+    filter_for: Vec<(String, Filter)>,
+
+    /// Scale pngs that look like pixel art with nearest neighbour, and everything else with --filter
+    #[arg(long)]
+    auto_filter: bool,
+
+    /// Pngs with more colors than this never count as pixel art for --auto-filter and --filter auto
+    #[arg(long, default_value_t = 256, hide_short_help = true)]
+    pixel_art_max_colors: usize,
+
+    /// Pngs wider or taller than this never count as pixel art for --auto-filter and --filter auto
+    #[arg(long, default_value_t = 512, hide_short_help = true)]
+    pixel_art_max_dimension: u32,
+
+    /// Sharpen pngs after scaling them down, to counteract the softer look of the Gaussian and Triangle filters.
+    /// AMOUNT is the radius of the unsharp mask, 0.5 if left out. 0 disables it
+    #[arg(long, value_name = "AMOUNT", num_args = 0..=1, default_missing_value = "0.5")]
+    sharpen: Option<f32>,
+
+    /// Pad pngs on the right and bottom up to the next multiple of N pixels on each axis after resizing them, e.g. 4
+    /// for GPU block compression. Padding is transparent, or --background for pngs without alpha
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pad_to_multiple: Option<u32>,
+
+    /// Maximum number of worker threads, which is how many pngs get encoded at once. Defaults to the number of
+    /// logical CPUs, or a couple on spinning disks
+    #[arg(short, long, alias = "encode-jobs")]
+    jobs: Option<usize>,
+
+    /// Only let this many workers decode at once, since decoding is where the memory goes. The rest carry on encoding
+    /// images already decoded
+    #[arg(long, alias = "parallel-decode-limit", value_parser = clap::value_parser!(u64).range(1..), value_name = "JOBS")]
+    decode_jobs: Option<u64>,
+
+    /// Storage the pngs are on, which decides how many workers are used when --jobs isn't given
+    #[arg(long, default_value_t, value_enum)]
+    disk_type: DiskType,
+
+    /// Skip paths matching this glob, relative to the search directory. Supports `*` and `**`, and may be repeated
+    #[arg(short, long)]
+    exclude: Vec<String>,
+
+    /// Process the newline separated paths listed in this file, or on stdin for `-`, instead of searching for pngs
+    #[arg(long, value_name = "PATH", conflicts_with = "watch")]
+    files_from: Option<String>,
+
+    /// Process just this file instead of searching for pngs
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["watch", "files_from"])]
+    file: Option<PathBuf>,
+
+    /// Squash the pngs inside this .zip, .tar, .tar.gz or .tgz instead of searching for pngs, and write the result to
+    /// --archive-out. Everything else in it is copied over as it is
+    #[arg(long, value_name = "PATH", requires = "archive_out", conflicts_with_all = [
+        "watch", "files_from", "file", "out_dir", "resume", "cache", "scales", "quarantine", "keep_larger_in", "backup", "format", "sample",
+        "dedupe", "stats_only", "preview", "bench", "max_total_bytes",
+    ])]
+    archive: Option<PathBuf>,
+
+    /// Where --archive writes the squashed archive. Zips stay zips and tars stay tars, gzipped or not by the extension
+    #[arg(long, value_name = "PATH", requires = "archive")]
+    archive_out: Option<PathBuf>,
+
+    /// Only process pngs matching this glob, relative to the search directory. May be repeated. --exclude still wins
+    #[arg(short, long)]
+    include: Vec<String>,
+
+    /// How many directory levels below the search directory to descend. 0 only processes pngs directly inside it
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Only process pngs directly inside the search directory. Same as --max-depth 0
+    #[arg(long, conflicts_with = "max_depth")]
+    no_recursive: bool,
+
+    /// Descend into symlinked directories. Directories are still only visited once, so symlink loops are safe
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Also process pngs that don't have a .png extension, recognised by their contents
+    #[arg(long)]
+    detect_content: bool,
+
+    /// Image formats to pick up, comma separated. Gifs and bmps are squashed into --format next to the original
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "png")]
+    input_formats: Vec<InputFormat>,
+
+    /// What to do with animated gifs
+    #[arg(long, default_value_t, value_enum)]
+    animated_gifs: AnimatedGifs,
+
+    /// Squash every frame of animated pngs. Without this they're skipped, since only the first frame would survive
+    #[arg(long)]
+    apng: bool,
+
+    /// Skip files and directories ignored by .gitignore files, including nested ones
+    #[arg(long)]
+    respect_gitignore: bool,
+
+    /// Write compressed pngs to this directory, mirroring the search directory's layout, instead of overwriting them
+    #[arg(short, long)]
+    out_dir: Option<PathBuf>,
+
+    /// Add this to the start of output file names. Without --out-dir the original is kept alongside the output
+    #[arg(long, value_name = "STR")]
+    prefix: Option<String>,
+
+    /// Add this to output file names, before the extension, e.g. `.min` for icon.min.png. Without --out-dir the
+    /// original is kept alongside the output
+    #[arg(long, value_name = "STR")]
+    suffix: Option<String>,
+
+    /// Skip pngs whose output in --out-dir is already there and decodes in full, so an interrupted run can pick up
+    /// where it left off. Outputs that are truncated, the wrong size, or older than their png are redone
+    #[arg(long, requires = "out_dir")]
+    resume: bool,
+
+    /// Move pngs that fail to decode, like truncated ones, into this directory, mirroring the search directory's
+    /// layout, so they're easy to find after the run. Without it they're only reported
+    #[arg(long, value_name = "DIR")]
+    quarantine: Option<PathBuf>,
+
+    /// Copy pngs that recompressing didn't make any smaller into this directory, mirroring the search directory's
+    /// layout, to review the ones that need another approach
+    #[arg(long, value_name = "DIR")]
+    keep_larger_in: Option<PathBuf>,
+
+    /// Resize pngs over 16 megapixels on this many threads. Helps when a few huge images dominate the run
+    #[arg(long, alias = "threads-per-image", value_name = "THREADS")]
+    big_image_threads: Option<usize>,
+
+    /// Hold off on starting pngs while the decoded images already in flight would take more than this much memory.
+    /// Accepts k, M and G suffixes
+    #[arg(long, value_parser = parse_size, value_name = "BYTES")]
+    memory_budget: Option<u64>,
+
+    /// Leave pngs smaller than this untouched. Accepts k, M and G suffixes
+    #[arg(long, value_parser = parse_size, default_value = "0")]
+    min_size: u64,
+
+    /// Leave pngs alone unless their longer side is at least this many pixels
+    #[arg(long, alias = "exclude-smaller-than", value_name = "PIXELS")]
+    min_dimension: Option<u32>,
+
+    /// Leave pngs alone if their longer side is more than this many pixels
+    #[arg(long, alias = "exclude-larger-than", value_name = "PIXELS")]
+    max_dimension: Option<u32>,
+
+    /// Leave pngs that already fit within --x-max, --y-max, --max-pixels and --target-size untouched instead of
+    /// re-encoding them at the same size. --force re-encodes them anyway
+    #[arg(long, alias = "downscale-only-if-larger-than")]
+    skip_within_limits: bool,
+
+    /// Alpha is stripped from pngs whose pixels are all at least this opaque. 255 strips it only if the png is fully opaque
+    #[arg(long, default_value_t = 254)]
+    alpha_threshold: u8,
+
+    /// Always keep the alpha channel, even for pngs that are effectively opaque
+    #[arg(long)]
+    keep_alpha: bool,
+
+    /// Bits per channel to keep in 16 bit pngs. auto drops to 8 when no value needs more
+    #[arg(long, default_value_t, value_enum)]
+    bit_depth: BitDepth,
+
+    /// Convert color pngs whose pixels are all gray to grayscale, keeping any alpha
+    #[arg(long)]
+    auto_grayscale: bool,
+
+    /// Store each png with the fewest channels that hold it exactly, dropping color from gray images and alpha from
+    /// fully opaque ones. Lossless, unlike --alpha-threshold
+    #[arg(long)]
+    channel_optimize: bool,
+
+    /// Leave pixels as they're stored instead of rotating and flipping them upright by the EXIF orientation tag. The tag
+    /// is carried over as it is, so viewers still turn them upright
+    #[arg(long)]
+    no_auto_orient: bool,
+
+    /// Crop away fully transparent rows and columns at the edges of pngs before resizing them. The offset of what's
+    /// kept is logged with -v, for lining sprites back up
+    #[arg(long)]
+    trim: bool,
+
+    /// Output encoding. Non-png outputs get the matching extension, and in place they take over from the original png,
+    /// which is deleted once the new file is written, or kept as its --backup
+    #[arg(long, default_value_t, value_enum)]
+    format: Format,
+
+    /// Quality of --format jpeg output, from 1 to 100
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), default_value = "85")]
+    quality: u8,
+
+    /// Color to composite transparent pngs over, as #RRGGBB. Only used when --format can't hold alpha, like jpeg, so
+    /// the transparency would otherwise be lost, and to fill --pad-to-multiple's padding on pngs without alpha
+    #[arg(long, value_parser = parse_color, default_value = "#ffffff", value_name = "#RRGGBB")]
+    background: [u8; 3],
+
+    /// Give outputs the extension of their format. With --replace-extension=false they keep the original name
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    replace_extension: bool,
+
+    /// How hard the png encoder works. Faster levels produce larger files
+    #[arg(long, default_value_t, value_enum)]
+    compression: Compression,
+
+    /// Row filter strategy for the png encoder
+    #[arg(long, default_value_t, value_enum)]
+    row_filter: RowFilter,
+
+    /// Interlacing of png output. adam7 lets pngs load progressively on the web, at some cost in size
+    #[arg(long, default_value_t, value_enum)]
+    interlace: Interlace,
+
+    /// Also encode each png with every row filter at the best and default compression, keeping the smallest. Lossless,
+    /// but about a dozen times the encoding work
+    #[arg(long)]
+    try_all_filters: bool,
+
+    /// Lossy: reduce each png to a palette of at most COLORS colors (2-256). Never kept if it ends up larger
+    #[arg(long, value_name = "COLORS", num_args = 0..=1, default_missing_value = "256", value_parser = clap::value_parser!(u16).range(2..=256))]
+    quantize: Option<u16>,
+
+    /// Lossy: reduce each color channel to LEVELS evenly spaced values (2-256), which deflates better than a palette
+    /// on gradient-heavy images. Smooth gradients start to band visibly below about 32 levels, and 64-128 is usually
+    /// hard to tell from the original. Never kept if it ends up larger
+    #[arg(long, value_name = "LEVELS", value_parser = clap::value_parser!(u16).range(2..=256))]
+    posterize: Option<u16>,
+
+    /// Dithering for the lossy steps: --quantize, --posterize and --bit-depth 8 on 16 bit images. Smooths gradients at
+    /// some cost in size. Defaults to floyd-steinberg for --quantize and none for the others. Lossless runs ignore it
+    #[arg(long, value_enum)]
+    dither: Option<Dither>,
+
+    /// Drop text metadata such as Title, Author and Copyright, and EXIF, instead of carrying it over to the compressed png
+    #[arg(long)]
+    strip_metadata: bool,
+
+    /// Drop embedded ICC color profiles, which are otherwise copied verbatim. Saves space, but color-managed viewers will
+    /// then assume sRGB, so pngs made for wider gamuts can look washed out or oversaturated
+    #[arg(long)]
+    strip_icc: bool,
+
+    /// Write only the chunks needed to display each png (IHDR, PLTE, tRNS, IDAT, IEND), dropping all metadata
+    #[arg(long)]
+    strip_all: bool,
+
+    /// Mark png output as this many dots per inch. Without it, a png's own pHYs chunk is scaled along with it so it
+    /// keeps the same physical size
+    #[arg(long, conflicts_with = "strip_all", value_parser = clap::value_parser!(u32).range(1..))]
+    dpi: Option<u32>,
+
+    /// Convert pngs to this display gamma, e.g. 2.2, from the one their gAMA chunk declares, so assets from different
+    /// tools come out equally bright. Images without a gAMA are taken to be sRGB. Png output is marked with the new
+    /// gamma
+    #[arg(long, value_name = "VALUE", value_parser = parse_gamma)]
+    normalize_gamma: Option<f64>,
+
+    /// Keep each png's modified and accessed times, so mtime-based build systems don't see a change
+    #[arg(long)]
+    preserve_mtime: bool,
+
+    /// Read default settings from this file instead of the squasher.toml found in the search directory or the closest
+    /// of its ancestors. Flags given on the command line win over the file, including its settings they can't be
+    /// combined with. Relative paths in it are relative to the file itself
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Log the decisions made for each png to stderr. Repeat for more detail
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Don't show progress while running
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print one JSON object per png and a final summary object instead of human readable output
+    #[arg(long)]
+    json: bool,
+
+    /// Instead of human readable output, print the path of each png processed to stdout and of each that failed to
+    /// stderr, each ending in a NUL rather than a newline, for `xargs -0`. Why a png failed is logged with -v
+    #[arg(long, conflicts_with = "json")]
+    print0: bool,
+
+    /// Once every png is written, replace outputs that are identical to another with hard links to it
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Write a spreadsheet of per-png results to this CSV file once the run is over
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Stop handing out new pngs as soon as one fails
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Give up on a png that takes longer than this many seconds and report it as failed
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+
+    /// After the first pass, keep running and compress pngs as they are added or changed
+    #[arg(long)]
+    watch: bool,
+
+    /// If another run is already working in a search directory or the --out-dir, wait for it to finish instead of exiting
+    #[arg(long)]
+    lock_wait: bool,
+
+    /// Report what the search directories hold, like sizes, dimensions and alpha usage, without compressing anything
+    #[arg(long, conflicts_with = "watch")]
+    stats_only: bool,
+
+    /// Write side by side comparisons of each resize filter into this directory instead of compressing anything. Each
+    /// shows a crop of the original next to the same crop of every filter's result, labelled with its size
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["watch", "stats_only"])]
+    preview: Option<PathBuf>,
+
+    /// Encode the pngs found with every resize filter and compression level, then print the average size and time of
+    /// each combination instead of compressing anything. Combine with --sample to bench a few pngs from a big tree
+    #[arg(long, conflicts_with_all = ["watch", "stats_only", "preview"])]
+    bench: bool,
+
+    /// How many pngs --preview samples, spread evenly over the ones found
+    #[arg(long, value_name = "N", default_value = "4")]
+    preview_count: usize,
+
+    /// Remember what was compressed in this JSON manifest, and skip pngs that haven't changed since
+    #[arg(long, value_name = "PATH")]
+    cache: Option<PathBuf>,
+
+    /// Report the projected savings for each png without overwriting anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Decode every output in full before it replaces anything. Pngs whose output doesn't decode to the right size
+    /// are left as they were and reported as errors. Avif output can't be decoded, so it isn't checked
+    #[arg(long)]
+    verify: bool,
+
+    /// Retry replacing a png this many times, waiting twice as long each time, when the filesystem fails in a way
+    /// that's likely to pass, as network mounts sometimes do
+    #[arg(long, value_name = "N", default_value = "3")]
+    retries: u32,
+
+    /// Copy each png to `<name>.png<SUFFIX>` before overwriting it. Pngs converted to another --format in place are
+    /// moved there instead of deleted
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, default_missing_value = ".bak")]
+    backup: Option<String>,
+
+    /// Overwrite backups that already exist
+    #[arg(long)]
+    overwrite_backups: bool,
+
+    /// Always replace pngs with the re-encode, even when it's bigger. Useful to get a whole tree onto the same
+    /// filter or format. Also implies --overwrite-backups
+    #[arg(long)]
+    force: bool,
+
+    /// Only replace a png when the re-encode is at least this many percent smaller. Below that the original is kept
+    /// untouched
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..100), default_value = "0")]
+    overwrite_threshold: u8,
+}
+
+impl Args {
+    /// True when stdout is meant for another program, so nothing but its output format goes there
+    fn machine_readable(&self) -> bool {
+        self.json || self.print0
+    }
 }
 
-fn load_and_preprocess(file_path: &String) -> Result<Vec<DynamicImage>, Box<dyn std::error::Error>> {
-    let loaded_image = ImageReader::open(file_path)?.decode()?;
-    if !loaded_image.color().has_alpha() {
-        return Ok(vec![loaded_image]);
+/// Parses a number with an optional k, M or G suffix, each `step` times the one before
+fn parse_with_suffix(s: &str, step: u64, what: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], step),
+        Some((i, 'm' | 'M')) => (&s[..i], step * step),
+        Some((i, 'g' | 'G')) => (&s[..i], step * step * step),
+        _ => (s, 1),
+    };
+    let value: f64 = digits.trim().parse().map_err(|_| format!("invalid {} `{}`", what, s))?;
+    if value < 0.0 {
+        return Err(format!("invalid {} `{}`", what, s));
     }
-    
-    if loaded_image.pixels().into_iter().any(|p| p.2.0[3] < 254) {
-        return Ok(vec![loaded_image]);
+    Ok((value * multiplier as f64) as u64)
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    parse_with_suffix(s, 1024, "size")
+}
+
+/// Pixel counts use decimal suffixes, so 2M is 2,000,000 pixels
+fn parse_pixel_count(s: &str) -> Result<u64, String> {
+    match parse_with_suffix(s, 1000, "pixel count")? {
+        0 => Err("must be at least 1 pixel".to_string()),
+        count => Ok(count),
+    }
+}
+
+/// A --scales entry, as the label it's named with and its value
+fn parse_scale(s: &str) -> Result<(String, f32), String> {
+    let label = s.trim().trim_start_matches('@').trim_end_matches('x');
+    match label.parse::<f32>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok((label.to_string(), value)),
+        _ => Err(format!("`{}` isn't a scale like 0.5 or @2x", s)),
+    }
+}
+
+/// A --normalize-gamma value, which has to be positive
+fn parse_gamma(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(gamma) if gamma > 0.0 && gamma.is_finite() => Ok(gamma),
+        _ => Err(format!("`{}` isn't a gamma like 2.2", s)),
+    }
+}
+
+fn parse_filter_for(s: &str) -> Result<(String, Filter), String> {
+    let (glob, filter) = s.rsplit_once('=').ok_or("expected GLOB=FILTER")?;
+    Ok((glob.to_string(), Filter::from_str(filter, true)?))
+}
+
+/// Parses a `#RRGGBB` hex color. The `#` is optional
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim().trim_start_matches('#');
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!("`{}` isn't a #RRGGBB color", s)),
+    }
+}
+
+/// Reads a --files-from list, making each entry absolute
+fn read_file_list(source: &str) -> io::Result<Vec<PathBuf>> {
+    let list = if source == "-" { io::read_to_string(io::stdin())? } else { fs::read_to_string(source)? };
+    list.lines().map(str::trim).filter(|line| !line.is_empty()).map(path::absolute).collect()
+}
+
+/// Checks a --files-from entry and turns it into the same form the search produces, under the first search directory
+/// that holds it. `roots` pairs each search directory with its absolute path
+fn listed_png(path: PathBuf, roots: &[(PathBuf, PathBuf)], mirrored: bool, scan: &ScanOptions) -> Option<PathBuf> {
+    if !path.is_file() {
+        warn!("{}: doesn't exist, skipping", path.display());
+        return None;
+    }
+    if !scan.accepts(&path) {
+        warn!("{}: isn't one of the --input-formats, skipping", path.display());
+        return None;
+    }
+    let under_root = roots.iter().find_map(|(root, absolute)| Some(root.join(path.strip_prefix(absolute).ok()?)));
+    match under_root {
+        Some(png) => Some(png),
+        None if mirrored => {
+            warn!("{}: is outside the search directories, so it can't be mirrored into --out-dir, skipping", path.display());
+            None
+        },
+        None => Some(path),
+    }
+}
+
+/// Like `format_bytes`, but for savings that can go negative when --force keeps bigger files
+fn format_saved(saved: i64) -> String {
+    let sign = if saved < 0 { "-" } else { "" };
+    format!("{}{}", sign, format_bytes(saved.unsigned_abs()))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
     } else {
-        let mut stripped_image = RgbImage::new(loaded_image.width(), loaded_image.height());
-        for pixel in loaded_image.pixels() {
-            stripped_image.put_pixel(pixel.0, pixel.1, Rgb([pixel.2.0[0], pixel.2.0[1], pixel.2.0[2]]));
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+/// What happened to a single png, as reported back by a worker
+enum Outcome {
+    /// Re-encoded into something smaller
+    Shrunk(CompressStats),
+    /// Didn't get any smaller, so the original was kept, or replaced anyway under --force
+    Unchanged(CompressStats),
+    /// Left alone before being decoded, for being animated, already within the size limits, or outside --min-size or the dimension limits
+    Filtered(CompressStats),
+    /// --cache says it hasn't changed since the last run
+    Cached,
+    /// --resume found its output already written
+    Resumed,
+    /// Never started because what was written already reached --max-total-bytes
+    OverBudget,
+    /// Errors aren't Send, so only their message makes it back to the reporting thread
+    Failed(String),
+}
+
+impl Outcome {
+    fn of(result: Result<CompressStats, SquashError>) -> Outcome {
+        match result {
+            Ok(stats) if stats.filtered => Outcome::Filtered(stats),
+            Ok(stats) if stats.new_size < stats.original_size => Outcome::Shrunk(stats),
+            Ok(stats) => Outcome::Unchanged(stats),
+            Err(e) => Outcome::Failed(e.to_string()),
+        }
+    }
+
+    /// Fails an outcome whose output is bigger than `cap`
+    fn capped(self, cap: Option<u64>) -> Outcome {
+        match (&self, cap) {
+            (Outcome::Shrunk(stats) | Outcome::Unchanged(stats) | Outcome::Filtered(stats), Some(cap)) => {
+                let size = if stats.skipped { stats.original_size } else { stats.new_size };
+                if size > cap {
+                    return Outcome::Failed(format!("output is {} bytes, over the --output-max-bytes cap of {}", size, cap));
+                }
+                self
+            },
+            _ => self,
         }
-        Ok(vec![loaded_image.clone(), stripped_image.into()])
     }
 }
 
-fn compress_image(loaded_image: DynamicImage, outfile_name: &String, nwidth: u32, nheight: u32, filter: Filter) -> Result<(), Box<dyn std::error::Error>> {
+const REPORT_HEADER: &str = "path,original_bytes,new_bytes,original_dims,new_dims,filter,skipped,error";
 
-        let temp_path = NamedTempFile::new()?;
-        let smaller_image = resize(&loaded_image, nwidth, nheight, convert_filter(filter));
-        let png_encoder = PngEncoder::new_with_quality(&temp_path, image::codecs::png::CompressionType::Best, image::codecs::png::FilterType::Adaptive);
-        smaller_image.write_with_encoder(png_encoder)?;
+/// Quotes `field` if it holds anything that would break up a CSV row
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A --report row in the columns of `REPORT_HEADER`
+fn csv_row(png: &Path, outcome: &Outcome) -> String {
+    let dims = |dims: Option<(u32, u32)>| dims.map_or(String::new(), |(width, height)| format!("{}x{}", width, height));
+    let fields = match outcome {
+        Outcome::Shrunk(stats) | Outcome::Unchanged(stats) | Outcome::Filtered(stats) => [
+            stats.original_size.to_string(),
+            stats.new_size.to_string(),
+            dims(stats.original_dimensions),
+            dims(stats.new_dimensions),
+            stats.filter.and_then(|filter| filter.to_possible_value()).map_or(String::new(), |value| value.get_name().to_string()),
+            stats.skipped.to_string(),
+            String::new(),
+        ],
+        Outcome::Cached | Outcome::Resumed | Outcome::OverBudget => [String::new(), String::new(), String::new(), String::new(), String::new(), true.to_string(), String::new()],
+        Outcome::Failed(e) => [String::new(), String::new(), String::new(), String::new(), String::new(), false.to_string(), e.clone()],
+    };
+    std::iter::once(png.to_string_lossy().into_owned()).chain(fields).map(|field| csv_field(&field)).collect::<Vec<_>>().join(",")
+}
+
+fn json_result(png: &Path, outcome: &Outcome) -> String {
+    let png = png.to_string_lossy();
+    match outcome {
+        Outcome::Shrunk(stats) | Outcome::Unchanged(stats) | Outcome::Filtered(stats) => format!("{{\"path\":{},\"original_size\":{},\"new_size\":{},\"skipped\":{},\"filtered\":{},\"over_target\":{},\"cached\":false,\"error\":null}}",
+            json::string(&png), stats.original_size, stats.new_size, stats.skipped, stats.filtered, stats.over_target),
+        Outcome::Cached => format!("{{\"path\":{},\"original_size\":null,\"new_size\":null,\"skipped\":true,\"filtered\":false,\"over_target\":false,\"cached\":true,\"error\":null}}",
+            json::string(&png)),
+        Outcome::Resumed | Outcome::OverBudget => format!("{{\"path\":{},\"original_size\":null,\"new_size\":null,\"skipped\":true,\"filtered\":false,\"over_target\":false,\"cached\":false,\"error\":null}}",
+            json::string(&png)),
+        Outcome::Failed(e) => format!("{{\"path\":{},\"original_size\":null,\"new_size\":null,\"skipped\":false,\"filtered\":false,\"over_target\":false,\"cached\":false,\"error\":{}}}",
+            json::string(&png), json::string(e)),
+    }
+}
+
+/// Writes `png` out for --print0: to stdout if it was processed, to stderr if it failed, ending in a NUL either way. The
+/// path goes out byte for byte, so names that aren't valid UTF-8 survive too
+fn print0(png: &Path, outcome: &Outcome) {
+    let line = [png.as_os_str().as_encoded_bytes(), b"\0"].concat();
+    let _ = match outcome {
+        Outcome::Shrunk(_) | Outcome::Unchanged(_) => io::stdout().lock().write_all(&line),
+        Outcome::Failed(e) => {
+            info!("{}:{}", png.display(), e);
+            io::stderr().lock().write_all(&line)
+        },
+        Outcome::Filtered(_) | Outcome::Cached | Outcome::Resumed | Outcome::OverBudget => Ok(()),
+    };
+}
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+
+    let (mut args, config_warnings) = config::args()?;
+    logger::init(args.verbose);
+    for warning in config_warnings {
+        warn!("{}", warning);
+    }
+    interrupt::install();
 
-        if let Ok(true) = fs::exists(outfile_name) {
-            let target_metadata = fs::metadata(outfile_name)?;
-            let temp_metadata = fs::metadata(temp_path.path())?;
-            if target_metadata.file_size() < temp_metadata.file_size() {
-                return Ok(());
+    let mut out_dir = args.out_dir.take().map(path::absolute).transpose()?;
+    let listed = match (&args.files_from, &args.file) {
+        (Some(source), _) => Some(read_file_list(source)?),
+        (None, Some(file)) => Some(vec![path::absolute(file)?]),
+        (None, None) => None,
+    };
+    let cache_path = args.cache.clone();
+    if args.dir.is_empty() {
+        args.dir.push(PathBuf::from("."));
+    }
+    let roots = args.dir.iter().map(|root| Ok((root.clone(), path::absolute(root)?))).collect::<io::Result<Vec<_>>>()?;
+    if let Some(dir) = &out_dir {
+        let canonical = dir.canonicalize().ok();
+        let is_root = |root: &PathBuf| canonical.is_some() && root.canonicalize().ok() == canonical;
+        if roots.len() == 1 && is_root(&roots[0].1) {
+            out_dir = None;
+        } else if roots.iter().any(|(_, root)| is_root(root)) {
+            return Err("--out-dir can't be one of several search directories".into());
+        } else {
+            for (_, root) in &roots {
+                if let Ok(inside) = dir.strip_prefix(root) {
+                    // Don't pick up our own output on later runs
+                    args.exclude.push(inside.to_string_lossy().replace('\\', "/"));
+                }
             }
-            let mut perms = std::fs::metadata(outfile_name)?.permissions();
-            if perms.readonly() {
-                perms.set_readonly(false);
-                std::fs::set_permissions(outfile_name, perms)?;
+        }
+    }
+    args.quarantine = args.quarantine.take().map(path::absolute).transpose()?;
+    args.keep_larger_in = args.keep_larger_in.take().map(path::absolute).transpose()?;
+    for dir in [&args.quarantine, &args.keep_larger_in].into_iter().flatten() {
+        for (_, root) in &roots {
+            if let Ok(inside) = dir.strip_prefix(root) {
+                // Quarantined pngs would only fail again, and kept ones only come out larger again
+                args.exclude.push(inside.to_string_lossy().replace('\\', "/"));
             }
         }
-        Ok(std::fs::rename(temp_path.path(), outfile_name)?)
-}
-
-fn compress_images(infile_name: &String, outfile_name: &String, max_width: Option<u32>, max_height: Option<u32>, filter: Filter) -> Result<(), Box<dyn std::error::Error>> {
-    let loaded_images = load_and_preprocess(infile_name)?;
-    for loaded_image in loaded_images {
-        let (nwidth, nheight) = match (max_width, max_height) {
-            (None, None) => (loaded_image.width(), loaded_image.height()),
-            (None, Some(max_h)) => ((loaded_image.width() as f32 * (max_h as f32 / loaded_image.height() as f32)) as u32, max_h),
-            (Some(max_w), None) => (max_w, ((loaded_image.height() as f32 * (max_w as f32 / loaded_image.width() as f32)) as u32)),
-            (Some(max_w), Some(max_h)) => {
-                let w_ratio = (max_w as f32 / loaded_image.width() as f32).min(1.0);
-                let h_ratio = (max_h as f32 / loaded_image.height() as f32).min(1.0);
-                ((loaded_image.width() as f32 * w_ratio.min(h_ratio)) as u32,
-                (loaded_image.height() as f32 * w_ratio.min(h_ratio)) as u32)
-            },
-        };
+    }
+
+    let opts = CompressOptions {
+        max_width: args.x_max,
+        max_height: args.y_max,
+        max_pixels: args.max_pixels,
+        filter: args.filter,
+        auto_filter: args.auto_filter,
+        pixel_art_max_colors: args.pixel_art_max_colors,
+        pixel_art_max_dimension: args.pixel_art_max_dimension,
+        dry_run: args.dry_run,
+        verify: args.verify,
+        retries: args.retries,
+        backup: args.backup.clone(),
+        overwrite_backups: args.overwrite_backups || args.force,
+        force: args.force,
+        overwrite_threshold: args.overwrite_threshold,
+        min_size: args.min_size,
+        min_dimension: args.min_dimension,
+        max_dimension: args.max_dimension,
+        skip_within_limits: args.skip_within_limits,
+        alpha_threshold: args.alpha_threshold,
+        keep_alpha: args.keep_alpha,
+        auto_orient: !args.no_auto_orient,
+        trim: args.trim,
+        bit_depth: args.bit_depth,
+        auto_grayscale: args.auto_grayscale,
+        channel_optimize: args.channel_optimize,
+        format: args.format,
+        quality: args.quality,
+        background: args.background,
+        animated_gifs: args.animated_gifs,
+        apng: args.apng,
+        compression: args.compression,
+        row_filter: args.row_filter,
+        interlace: args.interlace,
+        try_all_filters: args.try_all_filters,
+        quantize: args.quantize,
+        posterize: args.posterize,
+        dither: args.dither,
+        percent: args.percent,
+        power_of_two: args.power_of_two,
+        scale: 1.0,
+        target_size: args.target_size,
+        preserve_mtime: args.preserve_mtime,
+        big_image_threads: args.big_image_threads,
+        sharpen: args.sharpen,
+        pad_to_multiple: args.pad_to_multiple,
+        strip_metadata: args.strip_metadata,
+        strip_icc: args.strip_icc,
+        strip_all: args.strip_all,
+        dpi: args.dpi,
+        normalize_gamma: args.normalize_gamma,
+        abandoned: None,
+    };
+
+    let scan = ScanOptions {
+        exclude: args.exclude.clone(),
+        include: args.include.clone(),
+        respect_gitignore: args.respect_gitignore,
+        max_depth: if args.no_recursive { Some(0) } else { args.max_depth },
+        follow_symlinks: args.follow_symlinks,
+        detect_content: args.detect_content,
+        input_formats: args.input_formats.clone(),
+    };
+
+    // Only runs that write anything need to keep others out, of the search directories and of --out-dir, where two runs
+    // with different sources can still collide
+    let _locks = if args.dry_run || args.stats_only || args.preview.is_some() || args.bench || args.archive.is_some() {
+        None
+    } else {
+        if let Some(dir) = &out_dir {
+            fs::create_dir_all(dir)?;
+        }
+        Some(lock::acquire(roots.iter().map(|(_, root)| root.as_path()).chain(out_dir.as_deref()), args.lock_wait)?)
+    };
+
+    let jobs = args.jobs.unwrap_or_else(|| {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        match disk::detect(args.disk_type, roots.iter().map(|(_, root)| root.as_path()).chain(out_dir.as_deref())) {
+            DiskType::Hdd => cpus.min(disk::HDD_JOBS),
+            _ => cpus,
+        }
+    }).max(1);
+    let run = Run {
+        args: &args,
+        opts: &opts,
+        out_dir,
+        pool: ThreadPoolBuilder::new().num_threads(jobs).build()?,
+        cancelled: AtomicBool::new(false),
+        cache: cache_path.map(|path| {
+            // --filter-for changes what some pngs come out as, so it's part of the settings when given
+            let settings = if args.filter_for.is_empty() { format!("{:?}", opts) } else { format!("{:?} {:?}", opts, args.filter_for) };
+            RwLock::new(Cache::load(path, settings))
+        }),
+        report: args.report.as_ref().map(|_| Mutex::new(vec![])),
+        budget: args.memory_budget.map(|limit| Arc::new(Budget::new(limit))),
+        decode_slots: args.decode_jobs.map(|jobs| Arc::new(Budget::new(jobs))),
+        written: AtomicU64::new(0),
+        outputs: (args.dedupe && !args.dry_run).then(|| Mutex::new(vec![])),
+    };
+
+    if let (Some(archive), Some(archive_out)) = (&args.archive, &args.archive_out) {
+        let totals = run.squash_archive(archive, archive_out).map_err(|e| format!("{}: {}", archive.display(), e))?;
+        print_summary(&totals, &args);
+        return Ok(exit_code(&totals));
+    }
+
+    let discovered: Box<dyn Iterator<Item = PathBuf> + Send> = match listed {
+        Some(listed) => Box::new(listed.into_iter().filter_map(|png| listed_png(png, &roots, run.out_dir.is_some(), &scan))),
+        None => Box::new(run.find_pngs(&scan)),
+    };
+    let mut claimed = HashSet::new();
+    let pngs = discovered.filter(|png| {
+        if run.out_dir.is_none() {
+            return true;
+        }
+        // Each search directory is mirrored into --out-dir on its own, so two of them can hold the same relative path
+        let output = run.output_path(png);
+        let unique = claimed.insert(output.clone());
+        if !unique {
+            warn!("{}: would be written to {}, same as a png from another search directory, skipping", png.display(), output.display());
+        }
+        unique
+    });
+    let mut sampled_from = None;
+    let pngs: Box<dyn Iterator<Item = PathBuf> + Send> = match args.sample {
+        Some(count) => {
+            let seed = args.seed.unwrap_or_else(|| fastrand::u64(..));
+            let (picked, found) = sample(pngs, count, &mut fastrand::Rng::with_seed(seed));
+            sampled_from = Some((found, seed));
+            Box::new(picked.into_iter())
+        },
+        None => Box::new(pngs),
+    };
+    // Sorting, previews, stats and benches need every png up front. Otherwise pngs go to the workers as soon as they're found
+    let sort = args.sort.or(args.max_total_bytes.map(|_| SortOrder::SizeDesc));
+    let pngs: Box<dyn Iterator<Item = PathBuf> + Send> = if sort.is_some() || args.preview.is_some() || args.stats_only || args.bench {
+        let mut pngs: Vec<PathBuf> = pngs.collect();
+        let size = |png: &PathBuf| fs::metadata(png).map_or(0, |metadata| metadata.len());
+        match sort {
+            Some(SortOrder::Name) => pngs.sort(),
+            Some(SortOrder::SizeDesc) => pngs.sort_by_cached_key(|png| Reverse(size(png))),
+            Some(SortOrder::SizeAsc) => pngs.sort_by_cached_key(size),
+            None => {},
+        }
+        if let Some(dir) = &args.preview {
+            let written = preview::write_all(&pngs, args.preview_count, &args.dir, dir, &opts);
+            println!("Wrote {} previews to {}", written.len(), dir.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        if args.stats_only {
+            stats::report(&pngs, opts.alpha_threshold, &run.pool);
+            return Ok(ExitCode::SUCCESS);
+        }
+        if args.bench {
+            bench::report(&pngs, &opts, &run.pool);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Box::new(pngs.into_iter())
+    } else {
+        Box::new(pngs)
+    };
+    let mut totals = run.compress_all(pngs);
+    totals.sampled_from = sampled_from;
+    let outputs = run.outputs.as_ref().map_or(vec![], |outputs| std::mem::take(&mut *outputs.lock().unwrap()));
+    print_summary(&totals, &args);
+    // An interrupted run stops as soon as it can, and linking can wait for the next one
+    if !outputs.is_empty() && !interrupt::interrupted() {
+        let (linked, freed) = dedupe::link_duplicates(&outputs);
+        if linked > 0 && !args.machine_readable() {
+            println!("Hard linked {} duplicate files, freeing {}", linked, format_bytes(freed));
+        }
+    }
+
+    if args.watch && !run.cancelled.load(Ordering::Relaxed) && !interrupt::interrupted() {
+        run.watch(&scan);
+    }
+    if let (Some(path), Some(report)) = (&args.report, &run.report) {
+        let rows = report.lock().unwrap();
+        fs::write(path, format!("{}\n{}", REPORT_HEADER, rows.iter().map(|row| format!("{}\n", row)).collect::<String>()))?;
+    }
+    Ok(exit_code(&totals))
+}
+
+fn exit_code(totals: &Totals) -> ExitCode {
+    if interrupt::interrupted() {
+        ExitCode::from(interrupt::EXIT_CODE)
+    } else if totals.errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
 
-        compress_image(loaded_image, outfile_name, nwidth, nheight, filter)?;
+/// Compresses `png` into `output`, holding one of `decode_slots` while it's decoded. A png that turns out to be corrupt
+/// is moved to `quarantine`, if given
+fn compress_file(png: &Path, output: &Path, opts: &CompressOptions, decode_slots: Option<&Arc<Budget>>, quarantine: Option<&Path>) -> Result<CompressStats, SquashError> {
+    let decoded = {
+        let _slot = decode_slots.map(|slots| slots.reserve(1));
+        decode_file(png, opts)
+    };
+    let decoded = match (decoded, quarantine) {
+        (Err(e @ SquashError::Decode(_)), Some(destination)) => {
+            if opts.dry_run || opts.abandoned.as_ref().is_some_and(|abandoned| abandoned.load(Ordering::Relaxed)) {
+                info!("{}: would be moved to {}", png.display(), destination.display());
+            } else {
+                match move_file(png, destination) {
+                    Ok(()) => warn!("{}: couldn't be decoded, moved it to {}", png.display(), destination.display()),
+                    Err(move_error) => warn!("{}: couldn't be moved to {}: {}", png.display(), destination.display(), move_error),
+                }
+            }
+            return Err(e);
+        },
+        (decoded, _) => decoded?,
+    };
+    encode_file(decoded, png, output, opts)
+}
+
+/// Removes `png` once it's been converted to another format in place, or with --backup moves it to its backup name
+fn remove_converted(png: &Path, opts: &CompressOptions) -> Result<(), SquashError> {
+    let Some(suffix) = &opts.backup else {
+        return Ok(fs::remove_file(png)?);
+    };
+    let mut backup_name = png.as_os_str().to_owned();
+    backup_name.push(suffix);
+    if !opts.overwrite_backups && fs::exists(&backup_name)? {
+        return Err(SquashError::BackupExists(backup_name.into()));
     }
+    Ok(fs::rename(png, backup_name)?)
+}
 
-    Ok(())
+/// `png` relative to whichever of `roots` it was found in, or just its file name if none
+fn relative_path<'a>(png: &'a Path, roots: &[PathBuf]) -> &'a Path {
+    roots.iter().find_map(|root| png.strip_prefix(root).ok()).or(png.file_name().map(Path::new)).unwrap_or(png)
 }
 
-fn find_png_paths(path: &String) -> Vec<String>  {
-    let res = std::fs::read_dir(path);
-    if res.is_err() {
-        return vec![];
+/// Moves `from` to `to`, creating its directory, without replacing anything already there
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file is already there"));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
     }
-    let entries : Vec<PathBuf> = res.unwrap().filter_map(Result::ok).map(|entry| entry.path()).collect();
-    let png_entries = entries.iter().filter_map(|entry| {
-        if let Some("png") = entry.extension()?.to_str() {
-            Some(entry)
+    // Renaming doesn't work across filesystems
+    fs::rename(from, to).or_else(|_| {
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    })
+}
+
+/// Picks `count` of `pngs` at random by reservoir sampling, keeping them in the order they were found. Also returns
+/// how many there were to pick from
+fn sample(pngs: impl Iterator<Item = PathBuf>, count: usize, rng: &mut fastrand::Rng) -> (Vec<PathBuf>, usize) {
+    let mut picked: Vec<(usize, PathBuf)> = Vec::with_capacity(count);
+    let mut found = 0;
+    for (i, png) in pngs.enumerate() {
+        found = i + 1;
+        if picked.len() < count {
+            picked.push((i, png));
         } else {
-            None
+            let slot = rng.usize(..=i);
+            if slot < count {
+                picked[slot] = (i, png);
+            }
         }
-    }).map(|path| {
-        path.as_os_str().to_string_lossy().to_string()
-    }).collect::<Vec<String>>();
+    }
+    picked.sort_by_key(|(i, _)| *i);
+    (picked.into_iter().map(|(_, png)| png).collect(), found)
+}
 
-    let dir_entries = entries.iter().filter_map(|entry| {
-        if entry.is_dir() {
-            Some(entry)
-        } else {
-            None
+/// Running totals for a batch of pngs
+#[derive(Default)]
+struct Totals {
+    shrunk: usize,
+    /// Pngs that recompressing didn't make any smaller
+    unchanged: usize,
+    filtered: usize,
+    errors: usize,
+    /// Pngs never started because the run was stopped early
+    cancelled: usize,
+    /// Pngs skipped because --cache says they haven't changed
+    cached: usize,
+    /// Pngs skipped because --resume found their output already written
+    resumed: usize,
+    /// How many pngs were found and the seed used, when --sample only picked some of them
+    sampled_from: Option<(usize, u64)>,
+    /// Pngs left untouched once --max-total-bytes was reached
+    over_budget: Vec<PathBuf>,
+    original_size: u64,
+    new_size: u64,
+}
+
+impl Totals {
+    fn add(&mut self, outcome: Outcome) {
+        let shrunk = matches!(outcome, Outcome::Shrunk(_));
+        match outcome {
+            Outcome::Failed(_) => self.errors += 1,
+            Outcome::Cached => self.cached += 1,
+            Outcome::Resumed => self.resumed += 1,
+            // compress_all lists these by path
+            Outcome::OverBudget => {},
+            Outcome::Filtered(_) => self.filtered += 1,
+            Outcome::Shrunk(stats) | Outcome::Unchanged(stats) => {
+                if shrunk {
+                    self.shrunk += 1;
+                } else {
+                    self.unchanged += 1;
+                }
+                self.original_size += stats.original_size;
+                self.new_size += if stats.skipped { stats.original_size } else { stats.new_size };
+            },
+        }
+    }
+}
+
+fn print_summary(totals: &Totals, args: &Args) {
+    let saved = totals.original_size as i64 - totals.new_size as i64;
+    if args.print0 {
+        return;
+    }
+    if args.json {
+        println!("{{\"summary\":{{\"processed\":{},\"shrunk\":{},\"unchanged\":{},\"filtered\":{},\"errors\":{},\"cancelled\":{},\"cached\":{},\"resumed\":{},\"over_budget\":{},\"sampled_from\":{},\"original_size\":{},\"new_size\":{},\"saved\":{}}}}}",
+            totals.shrunk + totals.unchanged, totals.shrunk, totals.unchanged, totals.filtered, totals.errors, totals.cancelled, totals.cached, totals.resumed, totals.over_budget.len(),
+            totals.sampled_from.map_or(String::from("null"), |(found, _)| found.to_string()), totals.original_size, totals.new_size, saved);
+        return;
+    }
+    println!("Processed {} files, saved {} ({:.0}%)", totals.shrunk + totals.unchanged, format_saved(saved), saved as f64 / totals.original_size.max(1) as f64 * 100.0);
+    println!("{} shrunk, {} unchanged, {} failed", totals.shrunk, totals.unchanged, totals.errors);
+    if let Some((found, seed)) = totals.sampled_from {
+        println!("This was a random sample of the {} files found. --seed {} picks the same one again", found, seed);
+    }
+    if totals.filtered > 0 {
+        println!("Left {} files untouched for being animated, already within the size limits, or outside --min-size or the dimension limits", totals.filtered);
+    }
+    if totals.cached > 0 {
+        println!("Skipped {} files that haven't changed since they were cached", totals.cached);
+    }
+    if totals.resumed > 0 {
+        println!("Skipped {} files already written to --out-dir by an earlier run", totals.resumed);
+    }
+    if totals.cancelled > 0 {
+        println!("Stopped early, {} files were never started", totals.cancelled);
+    }
+    if !totals.over_budget.is_empty() {
+        println!("Reached --max-total-bytes, leaving {} files untouched:", totals.over_budget.len());
+        for png in &totals.over_budget {
+            println!("  {}", png.display());
         }
-    }).map(|entry| {
-        entry.as_os_str().to_string_lossy().to_string()
-    }).collect::<Vec<String>>();
-    let child_pngs : Vec<String> = dir_entries.iter().map(find_png_paths).into_iter().flatten().collect();
-    png_entries.into_iter().chain(child_pngs).collect()
+    }
+}
+
+/// Size and modification time, enough to tell whether a png changed between scans
+type Stamp = (u64, Option<SystemTime>);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()))
+}
+
+/// Everything a batch of pngs is compressed with
+struct Run<'a> {
+    args: &'a Args,
+    opts: &'a CompressOptions,
+    out_dir: Option<PathBuf>,
+    pool: ThreadPool,
+    /// Set to stop handing out pngs, e.g. after the first error with --fail-fast
+    cancelled: AtomicBool,
+    cache: Option<RwLock<Cache>>,
+    /// --report rows, written out once the run is over
+    report: Option<Mutex<Vec<String>>>,
+    /// Caps the memory of the images being compressed at once with --memory-budget
+    budget: Option<Arc<Budget>>,
+    /// Caps the pngs being decoded at once with --decode-jobs. Shared with threads abandoned by --timeout
+    decode_slots: Option<Arc<Budget>>,
+    /// Bytes written so far, counted against --max-total-bytes
+    written: AtomicU64,
+    /// Outputs actually written, for --dedupe to look through once the run is over
+    outputs: Option<Mutex<Vec<PathBuf>>>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+impl Run<'_> {
+    /// Searches every --dir in turn. A png reachable from more than one is only kept the first time it's found, and
+    /// ones named like our own outputs are left out when writing in place
+    fn find_pngs<'a>(&'a self, scan: &'a ScanOptions) -> impl Iterator<Item = PathBuf> + Send + 'a {
+        let mut seen = HashSet::new();
+        self.args.dir.iter().flat_map(|root| find_png_paths(root, scan))
+            .filter(|png| !self.is_renamed_output(png))
+            .filter(move |png| seen.insert(fs::canonicalize(png).unwrap_or_else(|_| png.clone())))
+    }
+
+    /// True if `png` already carries --prefix, --suffix or one of the --scales densities while writing in place, so
+    /// it's the output of an earlier run. Under --out-dir those outputs never land in the search directories, so
+    /// sources named that way, like high-DPI icon@2x.png assets, are kept
+    fn is_renamed_output(&self, png: &Path) -> bool {
+        let Some(stem) = png.file_stem().map(|stem| stem.to_string_lossy()) else {
+            return false;
+        };
+        let renamed = self.out_dir.is_none()
+            && (self.args.prefix.as_deref().is_some_and(|prefix| !prefix.is_empty() && stem.starts_with(prefix))
+                || self.args.suffix.as_deref().is_some_and(|suffix| !suffix.is_empty() && stem.ends_with(suffix))
+                || self.args.scales.iter().any(|(label, _)| stem.ends_with(&format!("@{}x", label))));
+        if renamed {
+            info!("{}: named like the output of an earlier run, skipping it", png.display());
+        }
+        renamed
+    }
+
+    /// The options `png` is compressed with at `scale`: those of the run, with the filter of the first --filter-for
+    /// glob that matches its path relative to the search directory holding it
+    fn opts_for(&self, png: &Path, scale: f32) -> Cow<'_, CompressOptions> {
+        let relative = self.args.dir.iter().find_map(|root| png.strip_prefix(root).ok()).unwrap_or(png);
+        let relative = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        let filter = self.args.filter_for.iter().find(|(pattern, _)| glob::matches(pattern, &relative)).map(|(_, filter)| *filter);
+        if filter.is_none() && scale == self.opts.scale {
+            return Cow::Borrowed(self.opts);
+        }
+        let mut opts = CompressOptions { scale, ..self.opts.clone() };
+        if let Some(filter) = filter {
+            opts.filter = filter;
+            opts.auto_filter = false;
+        }
+        Cow::Owned(opts)
+    }
+
+    /// Every output written for `png`, with the scale it's written at: one per --scales entry, named like
+    /// icon@0.5x.png, or just `output_path` at full size
+    fn variants(&self, png: &Path) -> Vec<(PathBuf, f32)> {
+        let output = self.output_path(png);
+        if self.args.scales.is_empty() {
+            return vec![(output, 1.0)];
+        }
+        // Densities like @3x are relative to the largest one given, which is the png's own size
+        let largest = self.args.scales.iter().map(|(_, value)| *value).fold(1.0, f32::max);
+        self.args.scales.iter().map(|(label, value)| {
+            let mut name = output.file_stem().unwrap_or_default().to_owned();
+            name.push(format!("@{}x", label));
+            if let Some(extension) = output.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            (output.with_file_name(name), value / largest)
+        }).collect()
+    }
+
+    /// Where `png` is written. --out-dir mirrors it relative to the first search directory that holds it, which is the
+    /// one it was found under
+    fn output_path(&self, png: &Path) -> PathBuf {
+        let mut output = match &self.out_dir {
+            Some(dir) => dir.join(self.args.dir.iter().find_map(|root| png.strip_prefix(root).ok()).unwrap_or(png)),
+            None => png.to_path_buf(),
+        };
+        // Pngs keep their name, even ones found by content without a .png extension. Gifs and bmps get a new one
+        if self.args.replace_extension && (self.opts.format != Format::Png || !has_png_signature(png)) {
+            output.set_extension(self.opts.format.extension());
+        }
+        if (self.args.prefix.is_some() || self.args.suffix.is_some()) && let Some(stem) = output.file_stem() {
+            let mut name = OsString::from(self.args.prefix.as_deref().unwrap_or(""));
+            name.push(stem);
+            name.push(self.args.suffix.as_deref().unwrap_or(""));
+            if let Some(extension) = output.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            output.set_file_name(name);
+        }
+        output
+    }
+
+    /// Where --quarantine moves `png` if it can't be decoded
+    fn quarantine_path(&self, png: &Path) -> Option<PathBuf> {
+        self.args.quarantine.as_ref().map(|dir| dir.join(relative_path(png, &self.args.dir)))
+    }
+
+    /// True if `output` is a png converted to another format in place, which should replace the png once it's written.
+    /// Renamed outputs from --prefix or --suffix are meant to sit next to the original instead
+    fn converts_in_place(&self, png: &Path, output: &Path) -> bool {
+        self.out_dir.is_none() && self.args.prefix.is_none() && self.args.suffix.is_none() && self.args.scales.is_empty() && !self.opts.dry_run
+            && output != png && has_png_signature(png) && output.exists()
+    }
 
-    let args = Args::parse();
+    /// Compresses `png` into `output`, giving up on it after --timeout. The abandoned work can't be stopped, so it
+    /// carries on in the background until it finishes or the run ends, but writes nothing. It keeps `reservation`,
+    /// the --memory-budget held for `png`, until then
+    fn compress(&self, png: &Path, output: &Path, scale: f32, reservation: Option<&Arc<Reservation>>) -> Outcome {
+        let opts = self.opts_for(png, scale);
+        let quarantine = self.quarantine_path(png);
+        let Some(timeout) = self.args.timeout else {
+            return Outcome::of(compress_file(png, output, &opts, self.decode_slots.as_ref(), quarantine.as_deref()));
+        };
+        let (done_tx, done_rx) = mpsc::channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let opts = CompressOptions { abandoned: Some(Arc::clone(&abandoned)), ..opts.into_owned() };
+        let (png, output, decode_slots, reservation) = (png.to_path_buf(), output.to_path_buf(), self.decode_slots.clone(), reservation.cloned());
+        thread::spawn(move || {
+            let _reservation = reservation;
+            let _ = done_tx.send(Outcome::of(compress_file(&png, &output, &opts, decode_slots.as_ref(), quarantine.as_deref())));
+        });
+        match done_rx.recv_timeout(Duration::from_secs(timeout)) {
+            Ok(outcome) => outcome,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                abandoned.store(true, Ordering::Relaxed);
+                Outcome::Failed(format!("timed out after {} seconds", timeout))
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => Outcome::Failed("crashed while compressing".to_string()),
+        }
+    }
+
+    /// Compresses `pngs` on the pool, reporting each result as it comes in
+    fn compress_all(&self, pngs: impl Iterator<Item = PathBuf> + Send) -> Totals {
+        let (args, opts, cancelled) = (self.args, self.opts, &self.cancelled);
+        let (found, searched) = (&AtomicUsize::new(0), &AtomicBool::new(false));
+        // Pngs can still be coming out of a directory walk, so count them on the way to the workers. The walk carries on
+        // after a cancel, so the summary can say how many were never started
+        let pngs = pngs.inspect(|_| { found.fetch_add(1, Ordering::Relaxed); })
+            .chain(iter::from_fn(|| {
+                searched.store(true, Ordering::Relaxed);
+                None
+            }));
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::scope(|scope| {
+            // The pool works from a helper thread so this one is free to report results as they arrive. Bridging hands pngs
+            // out in order as they're found, so --sort decides which ones start first and workers don't wait for the walk
+            scope.spawn(move || self.pool.install(|| pngs.par_bridge().for_each_with(done_tx, |done_tx, png| {
+                if cancelled.load(Ordering::Relaxed) || interrupt::interrupted() {
+                    return;
+                }
+                let variants = self.variants(&png);
+                if let Some(cache) = &self.cache && cache.read().unwrap().is_fresh(&png, &variants[0].0) {
+                    let _ = done_tx.send(vec![(png, Outcome::Cached)]);
+                    return;
+                }
+                if args.resume && variants.iter().all(|(output, scale)| is_finished_output(&png, output, &self.opts_for(&png, *scale))) {
+                    let _ = done_tx.send(vec![(png, Outcome::Resumed)]);
+                    return;
+                }
+                if args.max_total_bytes.is_some_and(|max| self.written.load(Ordering::Relaxed) >= max) {
+                    let _ = done_tx.send(vec![(png, Outcome::OverBudget)]);
+                    return;
+                }
+                // Pngs whose size can't be worked out reserve nothing and fail on their own in compress_file
+                let reservation = self.budget.as_ref().map(|budget| Arc::new(budget.reserve(decoded_size(&png).unwrap_or(0))));
+                let mut results = vec![];
+                for (output, scale) in variants {
+                    let outcome = self.compress(&png, &output, scale, reservation.as_ref()).capped(args.output_max_bytes);
+                    // Only remember pngs that ended up with an output, so the rest get another look next time
+                    if let (Some(cache), Outcome::Shrunk(_) | Outcome::Unchanged(_)) = (&self.cache, &outcome) && !opts.dry_run && output.exists() {
+                        match cache::Entry::of(&png, &output) {
+                            Ok(entry) => cache.write().unwrap().insert(&png, entry),
+                            Err(e) => warn!("{}: couldn't be added to the cache: {}", png.display(), e),
+                        }
+                    }
+                    if let Outcome::Shrunk(stats) | Outcome::Unchanged(stats) = &outcome && !stats.skipped && self.converts_in_place(&png, &output) {
+                        info!("{}: written as {}, removing the original", png.display(), output.display());
+                        if let Err(e) = remove_converted(&png, opts) {
+                            warn!("{}: couldn't be removed after converting it: {}", png.display(), e);
+                        }
+                    }
+                    if let (Outcome::Unchanged(stats), Some(dir)) = (&outcome, &args.keep_larger_in) && stats.skipped && !opts.dry_run {
+                        let kept = dir.join(relative_path(&png, &args.dir));
+                        let copied = kept.parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| fs::copy(&png, &kept));
+                        if let Err(e) = copied {
+                            warn!("{}: couldn't be copied to {}: {}", png.display(), kept.display(), e);
+                        }
+                    }
+                    // Pngs kept as they were in place were never written, so they're left out of --dedupe
+                    if let (Some(outputs), Outcome::Shrunk(stats) | Outcome::Unchanged(stats)) = (&self.outputs, &outcome)
+                        && (!stats.skipped || output != png) && output.exists() {
+                        outputs.lock().unwrap().push(output.clone());
+                    }
+                    if let Outcome::Shrunk(stats) | Outcome::Unchanged(stats) = &outcome {
+                        self.written.fetch_add(if stats.skipped { stats.original_size } else { stats.new_size }, Ordering::Relaxed);
+                    }
+                    // Each variant is reported under its own name
+                    results.push((if args.scales.is_empty() { png.clone() } else { output }, outcome));
+                }
+                let _ = done_tx.send(results);
+            })));
+
+            let mut progress = Progress::new(0, args.quiet || args.machine_readable());
+            let mut totals = Totals::default();
+            let mut handled = 0;
+            let mut interrupt_noticed = false;
+            loop {
+                // Wake up now and then so Ctrl-C is acknowledged even while a slow png is in flight
+                let results = match done_rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok(done) => done,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if interrupt::interrupted() && !interrupt_noticed && !args.machine_readable() {
+                            progress.println("Interrupted, waiting for in-flight pngs to finish. Press Ctrl-C again to quit now");
+                        }
+                        interrupt_noticed |= interrupt::interrupted();
+                        continue;
+                    },
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                handled += 1;
+                for (png, outcome) in results {
+                    if args.json {
+                        println!("{}", json_result(&png, &outcome));
+                    } else if args.print0 {
+                        print0(&png, &outcome);
+                    }
+                    if let Some(report) = &self.report {
+                        report.lock().unwrap().push(csv_row(&png, &outcome));
+                    }
+                    match &outcome {
+                        Outcome::Failed(e) => {
+                            if !args.machine_readable() {
+                                progress.println(&format!("{}:{}", png.display(), e));
+                            }
+                            if args.fail_fast && !cancelled.swap(true, Ordering::Relaxed) && !args.machine_readable() {
+                                progress.println("Stopping after the first error, waiting for in-flight pngs to finish");
+                            }
+                        },
+                        Outcome::OverBudget => totals.over_budget.push(png.clone()),
+                        Outcome::Cached | Outcome::Resumed | Outcome::Filtered(_) => {},
+                        Outcome::Shrunk(stats) | Outcome::Unchanged(stats) => {
+                            if args.dry_run && !args.machine_readable() {
+                                let saved = stats.original_size as i64 - stats.new_size as i64;
+                                progress.println(&format!("{}: {} -> {} bytes, saves {} bytes ({:.2}%)", png.display(), stats.original_size, stats.new_size, saved, saved as f32 / stats.original_size.max(1) as f32 * 100.0));
+                            }
+                            if stats.over_target && !args.machine_readable() {
+                                progress.println(&format!("{}: couldn't get it under --target-size, {} bytes at best", png.display(), stats.new_size));
+                            }
+                        },
+                    }
+                    totals.add(outcome);
+                }
+                progress.set_total(found.load(Ordering::Relaxed), searched.load(Ordering::Relaxed));
+                progress.inc();
+            }
+            progress.finish();
+            totals.cancelled = found.load(Ordering::Relaxed) - handled;
+            if let Some(cache) = &self.cache && let Err(e) = cache.read().unwrap().save() {
+                warn!("couldn't save the cache: {}", e);
+            }
+            totals
+        })
+    }
+
+    /// Squashes the pngs in the archive at `input` through temp files, then writes the archive back out to `output` with
+    /// the ones that shrank swapped in. Nothing is written under --dry-run
+    fn squash_archive(&self, input: &Path, output: &Path) -> io::Result<Totals> {
+        let mut archive = Archive::read(input)?;
+        archive.check_writable(output)?;
+        let temp_dir = tempfile::tempdir()?;
+        let results: Vec<(PathBuf, Outcome)> = self.pool.install(|| archive.entries.par_iter_mut().enumerate().filter(|(_, entry)| entry.is_png()).filter_map(|(i, entry)| {
+            if self.cancelled.load(Ordering::Relaxed) || interrupt::interrupted() {
+                return None;
+            }
+            // Entry names can climb out of any directory they're joined to, so temp files are numbered instead
+            let png = temp_dir.path().join(format!("{}.png", i));
+            let mut outcome = match entry.contents().and_then(|contents| fs::write(&png, contents)) {
+                Ok(()) => self.compress(&png, &png, 1.0, None).capped(self.args.output_max_bytes),
+                Err(e) => Outcome::Failed(e.to_string()),
+            };
+            if matches!(outcome, Outcome::Shrunk(_)) && !self.opts.dry_run && let Err(e) = fs::read(&png).map(|squashed| entry.replace(squashed)) {
+                outcome = Outcome::Failed(e.to_string());
+            }
+            let _ = fs::remove_file(&png);
+            // Entries are reported as if the archive were a directory
+            Some((input.join(&entry.name), outcome))
+        }).collect());
 
-    if let Some(path) = args.dir {
-        set_current_dir(path)?;
+        let mut totals = Totals::default();
+        for (png, outcome) in results {
+            if self.args.json {
+                println!("{}", json_result(&png, &outcome));
+            } else if self.args.print0 {
+                print0(&png, &outcome);
+            } else if let Outcome::Failed(e) = &outcome {
+                println!("{}:{}", png.display(), e);
+            }
+            if let Some(report) = &self.report {
+                report.lock().unwrap().push(csv_row(&png, &outcome));
+            }
+            totals.add(outcome);
+        }
+        if !self.opts.dry_run {
+            archive.write(output)?;
+        }
+        Ok(totals)
     }
 
-    let cwd = String::from(".");
-    let pngs = find_png_paths(&cwd);
-    let mut handles = vec![];
-    for png in pngs {
-        handles.push(thread::spawn(move || {
-            if let Err(e) =  compress_images(&png, &png, args.x_max, args.y_max, args.filter) {
-                println!("{}:{}", png, e);
+    /// Rescans every `WATCH_INTERVAL` and compresses pngs that are new or changed. A png is only picked up once it has
+    /// stayed the same for a whole interval, and our own writes are recorded so they don't set off another pass
+    fn watch(&self, scan: &ScanOptions) {
+        let mut known: HashMap<PathBuf, Stamp> = self.find_pngs(scan)
+            .filter_map(|png| Some((stamp(&png)?, png)))
+            .map(|(stamp, png)| (png, stamp))
+            .collect();
+        let mut pending: HashMap<PathBuf, Stamp> = HashMap::new();
+        if !self.args.machine_readable() {
+            println!("Watching for changes");
+        }
+        while !self.cancelled.load(Ordering::Relaxed) && !interrupt::interrupted() {
+            thread::sleep(WATCH_INTERVAL);
+            let mut settled = vec![];
+            for png in self.find_pngs(scan) {
+                let Some(current) = stamp(&png) else {
+                    continue;
+                };
+                if known.get(&png) == Some(&current) {
+                    pending.remove(&png);
+                } else if pending.get(&png) == Some(&current) {
+                    pending.remove(&png);
+                    settled.push(png);
+                } else {
+                    pending.insert(png, current);
+                }
             }
-        }));
-    }
-    let mut i = 0_f32;
-    let len = handles.len() as f32;
-    for handle in handles {
-        handle.is_finished();
-        let _ = handle.join();
-        println!("{:06.2}%", (i / len) * 100.0);
-        i = i + 1.0;
-    }
-    println!("{:06.2}%", 100.0);
-    Ok(())
-}
\ No newline at end of file
+            if settled.is_empty() {
+                continue;
+            }
+            let totals = self.compress_all(settled.clone().into_iter());
+            print_summary(&totals, self.args);
+            for png in settled {
+                if let Some(stamp) = stamp(&png) {
+                    known.insert(png, stamp);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    /// A run of `args` with `opts`, writing in place on a single thread
+    fn run<'a>(args: &'a Args, opts: &'a CompressOptions) -> Run<'a> {
+        Run {
+            args,
+            opts,
+            out_dir: None,
+            pool: ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
+            cancelled: AtomicBool::new(false),
+            cache: None,
+            report: None,
+            budget: None,
+            decode_slots: None,
+            written: AtomicU64::new(0),
+            outputs: None,
+        }
+    }
+
+    #[test]
+    fn backup_keeps_pngs_converted_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("icon.png");
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| Rgb([x as u8 * 8, y as u8 * 8, 0]))).save(&png).unwrap();
+        let args = Args::parse_from([OsString::from("png_squasher"), "--quiet".into(), "--backup".into(), "--format".into(), "webp".into(), "--dir".into(), dir.path().into()]);
+        let opts = CompressOptions { format: Format::Webp, backup: args.backup.clone(), force: true, ..Default::default() };
+        let totals = run(&args, &opts).compress_all(iter::once(png.clone()));
+        assert_eq!(totals.errors, 0);
+        assert!(dir.path().join("icon.webp").exists());
+        assert!(!png.exists());
+        assert!(dir.path().join("icon.png.bak").exists());
+    }
+}
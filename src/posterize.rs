@@ -0,0 +1,51 @@
+use image::{DynamicImage, ImageBuffer, Pixel};
+
+use crate::{dither::{self, Diffusion}, resize::Sample, Dither};
+
+fn posterize_buffer<P>(buffer: &mut ImageBuffer<P, Vec<P::Subpixel>>, levels: u16, has_alpha: bool, dither: Dither)
+where
+    P: Pixel,
+    P::Subpixel: Sample,
+{
+    let steps = (levels.max(2) - 1) as f32;
+    // Alpha is always the last channel
+    let color_channels = P::CHANNEL_COUNT as usize - has_alpha as usize;
+    let mut diffusion = Diffusion::new(buffer.width(), color_channels);
+    for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+        if x == 0 && y > 0 {
+            diffusion.next_row();
+        }
+        for (channel, value) in pixel.channels_mut()[..color_channels].iter_mut().enumerate() {
+            // In steps rather than sample values, so the error carries over the same at any bit depth
+            let wanted = match dither {
+                Dither::None => value.to_f32() / P::Subpixel::MAX * steps,
+                Dither::FloydSteinberg => (value.to_f32() / P::Subpixel::MAX * steps + diffusion.error(x, channel)).clamp(0.0, steps),
+                Dither::Ordered => (value.to_f32() / P::Subpixel::MAX * steps + dither::ordered_offset(x, y)).clamp(0.0, steps),
+            };
+            let level = wanted.round();
+            if dither == Dither::FloydSteinberg {
+                diffusion.spread(x, channel, wanted - level);
+            }
+            *value = P::Subpixel::from_f32(level / steps * P::Subpixel::MAX);
+        }
+    }
+}
+
+/// Snaps every color channel of `image` to the nearest of `levels` evenly spaced values, dithered as `dither` says.
+/// Alpha is left alone so edges don't turn jagged
+pub fn posterize(image: &mut DynamicImage, levels: u16, dither: Dither) {
+    let has_alpha = image.color().has_alpha();
+    match image {
+        DynamicImage::ImageLuma8(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageLumaA8(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageRgb8(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageRgba8(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageLuma16(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageLumaA16(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageRgb16(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageRgba16(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageRgb32F(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        DynamicImage::ImageRgba32F(buffer) => posterize_buffer(buffer, levels, has_alpha, dither),
+        _ => {},
+    }
+}
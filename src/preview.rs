@@ -0,0 +1,158 @@
+use std::{error::Error, fs, path::{Path, PathBuf}};
+
+use clap::ValueEnum;
+use image::{imageops, DynamicImage, GenericImageView, ImageReader, Rgba, RgbaImage};
+use log::warn;
+use png_squasher::{encode_image, target_dimensions, CompressOptions, Filter};
+
+use crate::{format_bytes, relative_path};
+
+/// Largest crop of the original shown in a preview, per side
+const CROP: u32 = 256;
+/// Space between and around panels
+const GAP: u32 = 4;
+/// Glyphs are 3x5 pixels, drawn at this scale
+const SCALE: u32 = 2;
+/// Width of a glyph plus the space after it
+const ADVANCE: u32 = 4 * SCALE;
+/// Height of a line of text plus the space under it
+const LINE: u32 = 6 * SCALE;
+const BACKGROUND: Rgba<u8> = Rgba([40, 40, 40, 255]);
+const TEXT: Rgba<u8> = Rgba([230, 230, 230, 255]);
+
+/// Rows of a 3x5 glyph, high bit on the left. Only covers what labels use: digits, filter names and byte units
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        _ => [0; 5],
+    }
+}
+
+fn draw_text(canvas: &mut RgbaImage, text: &str, x: u32, y: u32) {
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).into_iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..SCALE {
+                    for dx in 0..SCALE {
+                        let (px, py) = (x + i as u32 * ADVANCE + column * SCALE + dx, y + row as u32 * SCALE + dy);
+                        if px < canvas.width() && py < canvas.height() {
+                            canvas.put_pixel(px, py, TEXT);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// One labelled crop in a preview
+struct Panel {
+    crop: RgbaImage,
+    name: String,
+    size: u64,
+}
+
+/// The `crop_width` by `crop_height` region at `x0`, `y0` of an image `width` wide, taken from `resized` and blown back
+/// up with nearest neighbour so its pixels line up with the original's
+fn scaled_crop(resized: &DynamicImage, width: u32, height: u32, (x0, y0, crop_width, crop_height): (u32, u32, u32, u32)) -> RgbaImage {
+    let (resized_width, resized_height) = resized.dimensions();
+    RgbaImage::from_fn(crop_width, crop_height, |x, y| {
+        let sx = ((x0 + x) as u64 * resized_width as u64 / width as u64) as u32;
+        let sy = ((y0 + y) as u64 * resized_height as u64 / height as u64) as u32;
+        resized.get_pixel(sx.min(resized_width - 1), sy.min(resized_height - 1))
+    })
+}
+
+/// Writes a comparison of `png` resized with every filter to `out`: a crop from the middle of the original, then the
+/// same crop of each result, labelled with its encoded size
+fn preview(png: &Path, out: &Path, opts: &CompressOptions) -> Result<(), Box<dyn Error>> {
+    let original = image::open(png)?;
+    let (width, height) = original.dimensions();
+    let (nwidth, nheight) = target_dimensions(width, height, opts);
+    if (nwidth, nheight) == (width, height) {
+        warn!("{}: isn't being resized with these settings, so every filter looks the same", png.display());
+    }
+    let (crop_width, crop_height) = (width.min(CROP), height.min(CROP));
+    let region = ((width - crop_width) / 2, (height - crop_height) / 2, crop_width, crop_height);
+
+    let mut panels = vec![Panel {
+        crop: imageops::crop_imm(&original.to_rgba8(), region.0, region.1, crop_width, crop_height).to_image(),
+        name: "original".to_string(),
+        size: fs::metadata(png)?.len(),
+    }];
+    let temp_dir = std::env::temp_dir();
+    for filter in Filter::value_variants().iter().filter(|filter| !matches!(filter, Filter::Auto)) {
+        // Padding would throw off how the crops line up
+        let opts = CompressOptions { filter: *filter, auto_filter: false, pad_to_multiple: None, ..opts.clone() };
+        let encoded = encode_image(&original, nwidth, nheight, &temp_dir, &opts)?;
+        // Temp files have no extension to go by
+        let resized = ImageReader::open(encoded.path())?.with_guessed_format()?.decode()?;
+        panels.push(Panel {
+            crop: scaled_crop(&resized, width, height, region),
+            name: format!("{:?}", filter),
+            size: fs::metadata(encoded.path())?.len(),
+        });
+    }
+
+    let labels: Vec<(String, String)> = panels.iter().map(|panel| (panel.name.clone(), format_bytes(panel.size))).collect();
+    let label_width = labels.iter().flat_map(|(name, size)| [name.len(), size.len()]).max().unwrap_or(0) as u32 * ADVANCE;
+    let panel_width = crop_width.max(label_width);
+    let mut canvas = RgbaImage::from_pixel(GAP + panels.len() as u32 * (panel_width + GAP), GAP + crop_height + GAP + 2 * LINE, BACKGROUND);
+    for (i, (panel, (name, size))) in panels.iter().zip(&labels).enumerate() {
+        let x = GAP + i as u32 * (panel_width + GAP);
+        imageops::overlay(&mut canvas, &panel.crop, x as i64, GAP as i64);
+        draw_text(&mut canvas, name, x, GAP + crop_height + GAP);
+        draw_text(&mut canvas, size, x, GAP + crop_height + GAP + LINE);
+    }
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    canvas.save(out)?;
+    Ok(())
+}
+
+/// Writes previews for up to `count` pngs, spread evenly over `pngs`, into `dir`. Each is named after the png it
+/// shows, mirrored relative to its search directory like --out-dir. Returns the previews written
+pub fn write_all(pngs: &[PathBuf], count: usize, roots: &[PathBuf], dir: &Path, opts: &CompressOptions) -> Vec<PathBuf> {
+    let count = count.min(pngs.len());
+    (0..count).map(|i| &pngs[i * pngs.len() / count]).filter_map(|png| {
+        let mut out = dir.join(relative_path(png, roots));
+        out.set_extension("preview.png");
+        match preview(png, &out, opts) {
+            Ok(()) => Some(out),
+            Err(e) => {
+                warn!("{}: couldn't be previewed: {}", png.display(), e);
+                None
+            },
+        }
+    }).collect()
+}
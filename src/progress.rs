@@ -0,0 +1,95 @@
+use std::{io::{stdout, IsTerminal, Write}, time::{Duration, Instant}};
+
+const BAR_WIDTH: usize = 30;
+/// How often the bar is redrawn on a terminal
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// How often a plain progress line is written when stdout isn't a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tracks completed files and reports them as a single updating bar, or as occasional plain lines when piped
+pub struct Progress {
+    total: usize,
+    /// False while files are still being found, so the total may yet grow
+    total_known: bool,
+    done: usize,
+    start: Instant,
+    last_draw: Option<Instant>,
+    tty: bool,
+    quiet: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, quiet: bool) -> Progress {
+        Progress {
+            total,
+            total_known: true,
+            done: 0,
+            start: Instant::now(),
+            last_draw: None,
+            tty: stdout().is_terminal(),
+            quiet,
+        }
+    }
+
+    /// Updates the total as files are found. `known` says whether the search is over
+    pub fn set_total(&mut self, total: usize, known: bool) {
+        self.total = total;
+        self.total_known = known;
+    }
+
+    pub fn inc(&mut self) {
+        self.done += 1;
+        let interval = if self.tty { REDRAW_INTERVAL } else { LOG_INTERVAL };
+        if (self.total_known && self.done == self.total) || self.last_draw.is_none_or(|last| last.elapsed() >= interval) {
+            self.draw();
+        }
+    }
+
+    /// Prints a line above the bar without mangling it
+    pub fn println(&mut self, line: &str) {
+        if self.tty && !self.quiet {
+            print!("\r\x1b[2K");
+            println!("{}", line);
+            self.draw();
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    /// Leaves the finished bar on its own line
+    pub fn finish(&mut self) {
+        if self.quiet {
+            return;
+        }
+        if self.tty {
+            println!();
+        } else if self.last_draw.is_none() {
+            self.draw();
+        }
+    }
+
+    fn draw(&mut self) {
+        self.last_draw = Some(Instant::now());
+        if self.quiet {
+            return;
+        }
+        let fraction = if self.total == 0 { 1.0 } else { self.done as f64 / self.total as f64 };
+        let more = if self.total_known { "" } else { "+" };
+        if self.tty {
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            print!("\r\x1b[2K[{}{}] {}/{}{} {:5.1}% ETA {}", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), self.done, self.total, more, fraction * 100.0, self.eta());
+            let _ = stdout().flush();
+        } else {
+            println!("{:06.2}% ({}/{}{})", fraction * 100.0, self.done, self.total, more);
+        }
+    }
+
+    fn eta(&self) -> String {
+        if self.done == 0 || !self.total_known {
+            return String::from("--:--");
+        }
+        let remaining = self.start.elapsed().as_secs_f64() / self.done as f64 * (self.total - self.done) as f64;
+        let secs = remaining.round() as u64;
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
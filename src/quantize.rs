@@ -0,0 +1,143 @@
+use std::io::Write;
+
+use color_quant::NeuQuant;
+use image::{DynamicImage, RgbaImage};
+
+use crate::{dither::{self, Diffusion}, Compression, Dither, RowFilter, SquashError};
+
+/// Applies the png crate equivalents of `compression` and `row_filter` to `encoder`
+pub fn set_quality<W: Write>(encoder: &mut png::Encoder<W>, compression: Compression, row_filter: RowFilter) {
+    encoder.set_compression(match compression {
+        Compression::Fast => png::Compression::Fast,
+        Compression::Default => png::Compression::Default,
+        Compression::Best => png::Compression::Best,
+    });
+    match row_filter {
+        RowFilter::Adaptive => encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive),
+        RowFilter::NoFilter => encoder.set_filter(png::FilterType::NoFilter),
+        RowFilter::Sub => encoder.set_filter(png::FilterType::Sub),
+        RowFilter::Up => encoder.set_filter(png::FilterType::Up),
+        RowFilter::Avg => encoder.set_filter(png::FilterType::Avg),
+        RowFilter::Paeth => encoder.set_filter(png::FilterType::Paeth),
+    }
+}
+
+/// A palette image produced by `quantize`
+pub struct Indexed {
+    width: u32,
+    height: u32,
+    /// RGBA entries, with any translucent ones first so the tRNS chunk stays short
+    palette: Vec<[u8; 4]>,
+    indices: Vec<u8>,
+}
+
+/// The palette index of every pixel of `rgba`, dithered as `dither` says. Only the color channels are dithered, so
+/// edges don't turn noisy
+fn map_pixels(rgba: &RgbaImage, quant: &NeuQuant, colors: u16, dither: Dither) -> Vec<usize> {
+    if dither == Dither::None {
+        return rgba.pixels().map(|pixel| quant.index_of(&pixel.0)).collect();
+    }
+    let palette = quant.color_map_rgba();
+    // Rough distance between neighbouring palette colors on each channel, which is how far ordered dithering reaches
+    let spread = 255.0 / (colors as f32).cbrt();
+    let mut diffusion = Diffusion::new(rgba.width(), 3);
+    rgba.enumerate_pixels().map(|(x, y, pixel)| {
+        if x == 0 && y > 0 {
+            diffusion.next_row();
+        }
+        let mut wanted = [0.0_f32; 3];
+        for (channel, wanted) in wanted.iter_mut().enumerate() {
+            let offset = match dither {
+                Dither::Ordered => dither::ordered_offset(x, y) * spread,
+                _ => diffusion.error(x, channel),
+            };
+            *wanted = (pixel[channel] as f32 + offset).clamp(0.0, 255.0);
+        }
+        let index = quant.index_of(&[wanted[0].round() as u8, wanted[1].round() as u8, wanted[2].round() as u8, pixel[3]]);
+        if dither == Dither::FloydSteinberg {
+            for (channel, wanted) in wanted.iter().enumerate() {
+                diffusion.spread(x, channel, wanted - palette[index * 4 + channel] as f32);
+            }
+        }
+        index
+    }).collect()
+}
+
+/// Reduces `image` to at most `colors` colors with NeuQuant, dithered as `dither` says. Alpha is quantized along with
+/// the color channels
+pub fn quantize(image: &DynamicImage, colors: u16, dither: Dither) -> Indexed {
+    let rgba = image.to_rgba8();
+    let quant = NeuQuant::new(10, colors.clamp(2, 256) as usize, rgba.as_raw());
+    let raw_indices = map_pixels(&rgba, &quant, colors, dither);
+
+    // Drop unused entries, which also lets small palettes use a lower bit depth
+    let mut full_palette = quant.color_map_rgba();
+    if !image.color().has_alpha() {
+        // NeuQuant's alpha can drift slightly below opaque
+        full_palette.iter_mut().skip(3).step_by(4).for_each(|alpha| *alpha = 255);
+    }
+    let mut used = vec![false; full_palette.len() / 4];
+    for index in &raw_indices {
+        used[*index] = true;
+    }
+    let mut order: Vec<usize> = (0..used.len()).filter(|i| used[*i]).collect();
+    order.sort_by_key(|i| full_palette[i * 4 + 3] == 255);
+    let mut remap = vec![0_u8; used.len()];
+    for (new, old) in order.iter().enumerate() {
+        remap[*old] = new as u8;
+    }
+
+    Indexed {
+        width: rgba.width(),
+        height: rgba.height(),
+        palette: order.iter().map(|i| [full_palette[i * 4], full_palette[i * 4 + 1], full_palette[i * 4 + 2], full_palette[i * 4 + 3]]).collect(),
+        indices: raw_indices.iter().map(|index| remap[*index]).collect(),
+    }
+}
+
+impl Indexed {
+    pub fn encode(&self, compression: Compression, row_filter: RowFilter) -> Result<Vec<u8>, SquashError> {
+        let depth = match self.palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        };
+        let mut encoded = vec![];
+        let mut encoder = png::Encoder::new(&mut encoded, self.width, self.height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(match depth {
+            1 => png::BitDepth::One,
+            2 => png::BitDepth::Two,
+            4 => png::BitDepth::Four,
+            _ => png::BitDepth::Eight,
+        });
+        encoder.set_palette(self.palette.iter().flat_map(|entry| [entry[0], entry[1], entry[2]]).collect::<Vec<u8>>());
+        let trns: Vec<u8> = self.palette.iter().map(|entry| entry[3]).take_while(|alpha| *alpha < 255).collect();
+        if !trns.is_empty() {
+            encoder.set_trns(trns);
+        }
+        set_quality(&mut encoder, compression, row_filter);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.packed_rows(depth))?;
+        writer.finish()?;
+        Ok(encoded)
+    }
+
+    /// Packs the indices into rows of `depth` bit samples, each row starting on a fresh byte
+    fn packed_rows(&self, depth: usize) -> Vec<u8> {
+        if depth == 8 {
+            return self.indices.clone();
+        }
+        let per_byte = 8 / depth;
+        let row_bytes = (self.width as usize).div_ceil(per_byte);
+        let mut packed = vec![0_u8; row_bytes * self.height as usize];
+        for (y, row) in self.indices.chunks_exact(self.width as usize).enumerate() {
+            for (x, index) in row.iter().enumerate() {
+                let shift = 8 - depth * (x % per_byte + 1);
+                packed[y * row_bytes + x / per_byte] |= index << shift;
+            }
+        }
+        packed
+    }
+}
@@ -0,0 +1,238 @@
+use std::{f32::consts::PI, thread};
+
+use image::{imageops::FilterType, DynamicImage, ImageBuffer, Pixel};
+
+/// A channel type the resampler can read from and write back to
+pub trait Sample: Copy + Send + Sync {
+    const MIN: f32;
+    const MAX: f32;
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for u8 {
+    const MIN: f32 = 0.0;
+    const MAX: f32 = u8::MAX as f32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(value: f32) -> Self {
+        value.round() as u8
+    }
+}
+
+impl Sample for u16 {
+    const MIN: f32 = 0.0;
+    const MAX: f32 = u16::MAX as f32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(value: f32) -> Self {
+        value.round() as u16
+    }
+}
+
+impl Sample for f32 {
+    const MIN: f32 = 0.0;
+    const MAX: f32 = 1.0;
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+// The kernels below are the ones `image::imageops::resize` uses, so the results match it exactly
+
+fn sinc(t: f32) -> f32 {
+    let a = t * PI;
+    if t == 0.0 {
+        1.0
+    } else {
+        a.sin() / a
+    }
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() < 3.0 {
+        sinc(x) * sinc(x / 3.0)
+    } else {
+        0.0
+    }
+}
+
+fn catmull_rom(x: f32) -> f32 {
+    let (b, c) = (0.0_f32, 0.5_f32);
+    let a = x.abs();
+    let k = if a < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * a.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * a.powi(2)
+            + (6.0 - 2.0 * b)
+    } else if a < 2.0 {
+        (-b - 6.0 * c) * a.powi(3)
+            + (6.0 * b + 30.0 * c) * a.powi(2)
+            + (-12.0 * b - 48.0 * c) * a
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    k / 6.0
+}
+
+fn gaussian(x: f32) -> f32 {
+    let r = 0.5_f32;
+    ((2.0 * PI).sqrt() * r).recip() * (-x.powi(2) / (2.0 * r.powi(2))).exp()
+}
+
+fn triangle(x: f32) -> f32 {
+    if x.abs() < 1.0 {
+        1.0 - x.abs()
+    } else {
+        0.0
+    }
+}
+
+fn box_kernel(_x: f32) -> f32 {
+    1.0
+}
+
+fn kernel(filter: FilterType) -> (fn(f32) -> f32, f32) {
+    match filter {
+        FilterType::Nearest => (box_kernel, 0.0),
+        FilterType::Triangle => (triangle, 1.0),
+        FilterType::CatmullRom => (catmull_rom, 2.0),
+        FilterType::Gaussian => (gaussian, 3.0),
+        FilterType::Lanczos3 => (lanczos3, 3.0),
+    }
+}
+
+/// The first source index and normalized weights contributing to each of the `new_len` output positions
+fn weights(len: u32, new_len: u32, filter: FilterType) -> Vec<(usize, Vec<f32>)> {
+    let (kernel, support) = kernel(filter);
+    let ratio = len as f32 / new_len as f32;
+    let sratio = if ratio < 1.0 { 1.0 } else { ratio };
+    let src_support = support * sratio;
+    (0..new_len).map(|out| {
+        let input = (out as f32 + 0.5) * ratio;
+        let left = ((input - src_support).floor() as i64).clamp(0, len as i64 - 1);
+        let right = ((input + src_support).ceil() as i64).clamp(left + 1, len as i64);
+        let input = input - 0.5;
+        let mut ws: Vec<f32> = (left..right).map(|i| kernel((i as f32 - input) / sratio)).collect();
+        let sum: f32 = ws.iter().fold(0.0, |sum, w| sum + w);
+        ws.iter_mut().for_each(|w| *w /= sum);
+        (left as usize, ws)
+    }).collect()
+}
+
+fn clamp(value: f32, min: f32, max: f32) -> f32 {
+    if value < min {
+        min
+    } else if value > max {
+        max
+    } else {
+        value
+    }
+}
+
+/// Splits `rows` into at most `threads` bands of whole rows and runs `work` on each band on its own thread.
+/// `work` gets the index of the band's first row
+fn in_bands<T: Send>(rows: &mut [T], row_len: usize, threads: usize, work: impl Fn(usize, &mut [T]) + Sync) {
+    let row_count = rows.len() / row_len.max(1);
+    let band_rows = row_count.div_ceil(threads.max(1)).max(1);
+    thread::scope(|scope| {
+        for (band, chunk) in rows.chunks_mut(band_rows * row_len.max(1)).enumerate() {
+            let work = &work;
+            scope.spawn(move || work(band * band_rows, chunk));
+        }
+    });
+}
+
+fn resize_buffer<P>(image: &ImageBuffer<P, Vec<P::Subpixel>>, nwidth: u32, nheight: u32, filter: FilterType, threads: usize) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+    P::Subpixel: Sample,
+{
+    let (width, height) = image.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let source = image.as_raw();
+
+    // Columns first, into a float buffer of the source width and target height
+    let row_weights = weights(height, nheight, filter);
+    let tmp_row = width as usize * channels;
+    let mut tmp = vec![0.0_f32; tmp_row * nheight as usize];
+    in_bands(&mut tmp, tmp_row, threads, |first, band| {
+        for (offset, out_row) in band.chunks_mut(tmp_row).enumerate() {
+            let (left, ws) = &row_weights[first + offset];
+            for (i, w) in ws.iter().enumerate() {
+                let in_row = &source[(left + i) * tmp_row..][..tmp_row];
+                for (out, value) in out_row.iter_mut().zip(in_row) {
+                    *out += value.to_f32() * w;
+                }
+            }
+        }
+    });
+
+    // Then rows, into the target buffer
+    let column_weights = weights(width, nwidth, filter);
+    let out_row_len = nwidth as usize * channels;
+    let mut out = vec![P::Subpixel::from_f32(0.0); out_row_len * nheight as usize];
+    in_bands(&mut out, out_row_len, threads, |first, band| {
+        for (offset, out_row) in band.chunks_mut(out_row_len).enumerate() {
+            let in_row = &tmp[(first + offset) * tmp_row..][..tmp_row];
+            for (out_pixel, (left, ws)) in out_row.chunks_mut(channels).zip(&column_weights) {
+                for (c, out) in out_pixel.iter_mut().enumerate() {
+                    let mut t = 0.0;
+                    for (i, w) in ws.iter().enumerate() {
+                        t += in_row[(left + i) * channels + c] * w;
+                    }
+                    *out = P::Subpixel::from_f32(clamp(t, P::Subpixel::MIN, P::Subpixel::MAX));
+                }
+            }
+        }
+    });
+
+    ImageBuffer::from_raw(nwidth, nheight, out).expect("output buffer matches the target dimensions")
+}
+
+/// Same result as `DynamicImage::resize_exact`, with each pass split across `threads` threads
+pub fn resize(image: &DynamicImage, nwidth: u32, nheight: u32, filter: FilterType, threads: usize) -> DynamicImage {
+    if threads <= 1 || image.width() == 0 || image.height() == 0 || (nwidth, nheight) == (image.width(), image.height()) {
+        return image.resize_exact(nwidth, nheight, filter);
+    }
+    match image {
+        DynamicImage::ImageLuma8(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageLumaA8(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageRgb8(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageRgba8(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageLuma16(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageLumaA16(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageRgb16(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageRgba16(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageRgb32F(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        DynamicImage::ImageRgba32F(buffer) => resize_buffer(buffer, nwidth, nheight, filter, threads).into(),
+        _ => image.resize_exact(nwidth, nheight, filter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops, Rgb, Rgba};
+
+    const FILTERS: [FilterType; 5] = [FilterType::Nearest, FilterType::Triangle, FilterType::CatmullRom, FilterType::Gaussian, FilterType::Lanczos3];
+
+    #[test]
+    fn matches_imageops_resize() {
+        let rgba8 = ImageBuffer::from_fn(37, 23, |x, y| Rgba([(x * 7) as u8, (y * 11) as u8, ((x + y) * 5) as u8, (255 - x * 3) as u8]));
+        let rgb16 = ImageBuffer::from_fn(37, 23, |x, y| Rgb([(x * 1771) as u16, (y * 2849) as u16, ((x * y) * 77) as u16]));
+        for filter in FILTERS {
+            for (nwidth, nheight) in [(20, 11), (61, 40), (37, 9)] {
+                let resized = resize(&DynamicImage::ImageRgba8(rgba8.clone()), nwidth, nheight, filter, 4);
+                assert_eq!(resized.as_rgba8().unwrap(), &imageops::resize(&rgba8, nwidth, nheight, filter), "{:?} to {}x{}", filter, nwidth, nheight);
+                let resized = resize(&DynamicImage::ImageRgb16(rgb16.clone()), nwidth, nheight, filter, 4);
+                assert_eq!(resized.as_rgb16().unwrap(), &imageops::resize(&rgb16, nwidth, nheight, filter), "{:?} to {}x{}", filter, nwidth, nheight);
+            }
+        }
+    }
+}
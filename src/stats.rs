@@ -0,0 +1,57 @@
+use std::{cmp::Reverse, collections::BTreeMap, fs, path::PathBuf};
+
+use log::warn;
+use png_squasher::{inspect, ImageInfo};
+use rayon::{iter::{IntoParallelRefIterator, ParallelIterator}, ThreadPool};
+
+use crate::format_bytes;
+
+/// How many of the biggest pngs the report lists
+const LARGEST: usize = 10;
+
+/// Upper bounds on the longer side that the dimension breakdown groups pngs by
+const BUCKETS: [u32; 5] = [64, 256, 1024, 4096, 16384];
+
+/// Inspects every png on `pool` and prints what the tree holds: counts, sizes, dimensions, color types and how much
+/// alpha is actually used. Nothing is encoded or written
+pub fn report(pngs: &[PathBuf], alpha_threshold: u8, pool: &ThreadPool) {
+    let inspected: Vec<(&PathBuf, u64, Option<ImageInfo>)> = pool.install(|| pngs.par_iter().map(|png| {
+        let size = fs::metadata(png).map_or(0, |metadata| metadata.len());
+        let info = inspect(png, alpha_threshold).inspect_err(|e| warn!("{}: couldn't be read: {}", png.display(), e)).ok();
+        (png, size, info)
+    }).collect());
+
+    let total_size: u64 = inspected.iter().map(|(_, size, _)| size).sum();
+    let infos: Vec<&ImageInfo> = inspected.iter().filter_map(|(_, _, info)| info.as_ref()).collect();
+    println!("{} pngs, {} in total", inspected.len(), format_bytes(total_size));
+    if infos.len() < inspected.len() {
+        println!("{} couldn't be read", inspected.len() - infos.len());
+    }
+
+    println!("Longer side:");
+    let mut lower = 0;
+    for upper in BUCKETS {
+        let count = infos.iter().filter(|info| (lower..=upper).contains(&info.dimensions.0.max(info.dimensions.1))).count();
+        println!("  {:>5} - {:<5} px: {}", lower, upper, count);
+        lower = upper + 1;
+    }
+    println!("  {:>13} px: {}", format!("over {}", lower - 1), infos.iter().filter(|info| info.dimensions.0.max(info.dimensions.1) >= lower).count());
+
+    let mut colors: BTreeMap<String, usize> = BTreeMap::new();
+    for info in &infos {
+        *colors.entry(format!("{:?}", info.color)).or_default() += 1;
+    }
+    println!("Color types: {}", colors.iter().map(|(color, count)| format!("{} {}", count, color)).collect::<Vec<_>>().join(", "));
+
+    let with_alpha = infos.iter().filter(|info| info.opaque.is_some()).count();
+    let opaque = infos.iter().filter(|info| info.opaque == Some(true)).count();
+    println!("{} have an alpha channel, {} of them effectively opaque at --alpha-threshold {}", with_alpha, opaque, alpha_threshold);
+
+    let mut largest: Vec<_> = inspected.iter().collect();
+    largest.sort_by_key(|(_, size, _)| Reverse(*size));
+    println!("Largest:");
+    for (png, size, info) in largest.into_iter().take(LARGEST) {
+        let dimensions = info.as_ref().map_or("?".to_string(), |info| format!("{}x{}", info.dimensions.0, info.dimensions.1));
+        println!("  {:>10}  {:>11}  {}", format_bytes(*size), dimensions, png.display());
+    }
+}